@@ -1,5 +1,6 @@
 use criterion::{criterion_group, criterion_main, Criterion};
-use rust_debugging_locks::debugging_locks::RwLockWrapped;
+use rust_debugging_locks::debugging_locks::{set_diagnostics_enabled, RwLockWrapped};
+use std::sync::RwLock;
 
 fn rwlock_creation_and_use(c: &mut Criterion) {
     c.bench_function("create rwlock", |b| {
@@ -7,7 +8,41 @@ fn rwlock_creation_and_use(c: &mut Criterion) {
             let _lock = RwLockWrapped::new(());
         })
     });
+    let lock = RwLockWrapped::new(());
+    c.bench_function("read uncontended", |b| {
+        b.iter(|| {
+            let _guard = lock.read().unwrap();
+        })
+    });
+    c.bench_function("write uncontended", |b| {
+        b.iter(|| {
+            let _guard = lock.write().unwrap();
+        })
+    });
+}
+
+fn passthrough_vs_std(c: &mut Criterion) {
+    let std_lock = RwLock::new(());
+    c.bench_function("std read uncontended", |b| {
+        b.iter(|| {
+            let _guard = std_lock.read().unwrap();
+        })
+    });
+
+    set_diagnostics_enabled(false);
+    let lock = RwLockWrapped::new(());
+    c.bench_function("read uncontended passthrough", |b| {
+        b.iter(|| {
+            let _guard = lock.read().unwrap();
+        })
+    });
+    c.bench_function("write uncontended passthrough", |b| {
+        b.iter(|| {
+            let _guard = lock.write().unwrap();
+        })
+    });
+    set_diagnostics_enabled(true);
 }
 
-criterion_group!(benches, rwlock_creation_and_use);
+criterion_group!(benches, rwlock_creation_and_use, passthrough_vs_std);
 criterion_main!(benches);
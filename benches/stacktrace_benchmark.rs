@@ -1,9 +1,40 @@
 use criterion::{criterion_group, criterion_main, Criterion};
-use rust_debugging_locks::stacktrace_util::backtrack_frame;
+use rust_debugging_locks::stacktrace_util::{
+    backtrack_frame, capture_unresolved, clear_symbol_cache, symbol_cache_stats, Frame,
+};
+
+// no frame is skipped: the stack starts at the capturing function followed by its caller;
+// the frames above depend on criterion's call path (e.g. warm up vs. measurement), thus are not counted
+fn assert_start_frames(frames: &[Frame], capturing: &str, caller: &str) {
+    let methods: Vec<&str> = frames
+        .iter()
+        .take(2)
+        .map(|frame| frame.method.as_str())
+        .collect();
+    assert_eq!(vec![capturing, caller], methods);
+}
 
 fn dummy_start_frame() {
     let stacktrace = backtrack_frame(|symbol_name| symbol_name.contains("no_skip"));
-    assert_eq!(3, stacktrace.unwrap().frames.len());
+    assert_start_frames(
+        &stacktrace.unwrap().frames,
+        "rust_debugging_locks::stacktrace_util::backtrack_frame",
+        "stacktrace_benchmark::dummy_start_frame",
+    );
+}
+
+fn dummy_start_frame_unresolved() {
+    let stacktrace = capture_unresolved(|symbol_name| symbol_name.contains("no_skip"));
+    assert!(stacktrace.is_ok());
+}
+
+fn dummy_start_frame_unresolved_and_resolve() {
+    let stacktrace = capture_unresolved(|symbol_name| symbol_name.contains("no_skip")).unwrap();
+    assert_start_frames(
+        &stacktrace.resolve().unwrap().frames,
+        "rust_debugging_locks::stacktrace_util::capture_unresolved",
+        "stacktrace_benchmark::dummy_start_frame_unresolved_and_resolve",
+    );
 }
 
 fn backtrace_benchmark(c: &mut Criterion) {
@@ -21,8 +52,28 @@ fn backtrace_benchmark(c: &mut Criterion) {
             });
         })
     });
-    c.bench_function("stacktrace", |b| b.iter(|| dummy_start_frame()));
+    c.bench_function("stacktrace", |b| b.iter(dummy_start_frame));
+    c.bench_function("stacktrace cold symbol cache", |b| {
+        b.iter(|| {
+            clear_symbol_cache();
+            dummy_start_frame();
+        })
+    });
+    c.bench_function("stacktrace unresolved", |b| {
+        b.iter(dummy_start_frame_unresolved)
+    });
+    c.bench_function("stacktrace unresolved and resolve", |b| {
+        b.iter(dummy_start_frame_unresolved_and_resolve)
+    });
+}
+
+fn print_symbol_cache_stats(_c: &mut Criterion) {
+    let stats = symbol_cache_stats();
+    println!(
+        "symbol cache: {} hits, {} misses, {} entries",
+        stats.hits, stats.misses, stats.entries
+    );
 }
 
-criterion_group!(benches, backtrace_benchmark);
+criterion_group!(benches, backtrace_benchmark, print_symbol_cache_stats);
 criterion_main!(benches);
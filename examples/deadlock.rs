@@ -0,0 +1,39 @@
+use env_logger::Env;
+use rust_debugging_locks::deadlock_detector::start_deadlock_detector;
+use rust_debugging_locks::debugging_locks::RwLockWrapped;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+
+    start_deadlock_detector(Duration::from_millis(200));
+
+    let lock_a = Arc::new(RwLockWrapped::new(1));
+    let lock_b = Arc::new(RwLockWrapped::new(2));
+
+    let (a1, b1) = (lock_a.clone(), lock_b.clone());
+    thread::Builder::new()
+        .name("ab".to_string())
+        .spawn(move || {
+            let _a = a1.write().unwrap();
+            thread::sleep(Duration::from_millis(50));
+            let _b = b1.write().unwrap();
+        })
+        .unwrap();
+
+    let (a2, b2) = (lock_a.clone(), lock_b.clone());
+    thread::Builder::new()
+        .name("ba".to_string())
+        .spawn(move || {
+            let _b = b2.write().unwrap();
+            thread::sleep(Duration::from_millis(50));
+            let _a = a2.write().unwrap();
+        })
+        .unwrap();
+
+    // both threads stay blocked forever; exit after the detector had time to report
+    thread::sleep(Duration::from_millis(1000));
+    println!("exit with deadlocked threads");
+}
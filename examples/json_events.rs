@@ -0,0 +1,29 @@
+use env_logger::Env;
+use rust_debugging_locks::debugging_locks::RwLockWrapped;
+use rust_debugging_locks::json_events::JsonEventHandler;
+use rust_debugging_locks::lock_events::set_global_event_handler;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// cargo run --example json_events --features json
+fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+
+    set_global_event_handler(Arc::new(JsonEventHandler::with_writer(Box::new(
+        std::io::stdout(),
+    ))));
+
+    let lock = Arc::new(RwLockWrapped::new(0));
+
+    let l1 = lock.clone();
+    let thread = thread::spawn(move || {
+        let _w1 = l1.write().unwrap();
+        thread::sleep(Duration::from_millis(150));
+    });
+    // wait until w1 lock is acquired
+    thread::sleep(Duration::from_millis(50));
+
+    let _r = lock.read().unwrap();
+    thread.join().unwrap();
+}
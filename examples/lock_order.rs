@@ -0,0 +1,34 @@
+use env_logger::Env;
+use rust_debugging_locks::debugging_locks::MutexWrapped;
+use rust_debugging_locks::lock_order::enable_lock_order_validation;
+
+fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
+
+    enable_lock_order_validation();
+
+    let accounts = MutexWrapped::new(vec![1, 2, 3]);
+    let audit_log = MutexWrapped::new(Vec::<String>::new());
+
+    // establishes the order accounts -> audit_log
+    transfer(&accounts, &audit_log);
+    // inverted order audit_log -> accounts gets reported although it does not deadlock here
+    audit(&accounts, &audit_log);
+}
+
+fn transfer(accounts: &MutexWrapped<Vec<i32>>, audit_log: &MutexWrapped<Vec<String>>) {
+    let mut accounts = accounts.lock().unwrap();
+    accounts[0] -= 1;
+    accounts[1] += 1;
+    audit_log.lock().unwrap().push("transfer".to_string());
+}
+
+fn audit(accounts: &MutexWrapped<Vec<i32>>, audit_log: &MutexWrapped<Vec<String>>) {
+    let audit_log = audit_log.lock().unwrap();
+    let accounts = accounts.lock().unwrap();
+    println!(
+        "{} entries; total {}",
+        audit_log.len(),
+        accounts.iter().sum::<i32>()
+    );
+}
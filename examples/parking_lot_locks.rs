@@ -0,0 +1,54 @@
+use env_logger::Env;
+use rust_debugging_locks::parking_lot_locks::{
+    ParkingLotMutexWrapped, ParkingLotRwLockUpgradableReadGuardWrapped, ParkingLotRwLockWrapped,
+};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// cargo run --example parking_lot_locks --features parking_lot
+fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
+
+    upgrade_blocked_by_reader();
+    locker_times_out();
+}
+
+fn upgrade_blocked_by_reader() {
+    let lock = Arc::new(ParkingLotRwLockWrapped::new(vec![1, 2, 3]));
+
+    let l1 = lock.clone();
+    let thread = thread::spawn(move || {
+        let r1 = l1.read();
+        println!("acquire read lock {} ...", r1.len());
+        thread::sleep(Duration::from_millis(300));
+        println!("... release read lock.");
+    });
+    // wait until r1 lock is acquired
+    thread::sleep(Duration::from_millis(50));
+
+    let upgradable = lock.upgradable_read();
+    println!("upgrading lock ...");
+    let mut writer = ParkingLotRwLockUpgradableReadGuardWrapped::upgrade(upgradable);
+    writer.push(4);
+    println!("... lock upgraded: {:?}", *writer);
+
+    thread.join().unwrap();
+}
+
+fn locker_times_out() {
+    let mutex = Arc::new(ParkingLotMutexWrapped::new(0));
+
+    let m1 = mutex.clone();
+    let thread = thread::spawn(move || {
+        let _l1 = m1.lock();
+        thread::sleep(Duration::from_millis(300));
+    });
+    thread::sleep(Duration::from_millis(50));
+
+    println!("try locking mutex for 100ms ...");
+    let result = mutex.try_lock_for(Duration::from_millis(100));
+    println!("... timed out: {}", result.is_none());
+
+    thread.join().unwrap();
+}
@@ -1,5 +1,7 @@
 use env_logger::Env;
-use rust_debugging_locks::debugging_locks::RwLockWrapped;
+use rust_debugging_locks::debugging_locks::{MutexWrapped, RwLockWrapped};
+use rust_debugging_locks::thresholds_config::{set_thresholds_config, ThresholdsConfig};
+use rust_debugging_locks::watchdog;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::thread;
@@ -8,8 +10,14 @@ use std::time::Duration;
 fn main() {
     env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
 
+    set_thresholds_config(
+        ThresholdsConfig::from_env().with_held_too_long(Some(Duration::from_millis(1000))),
+    );
+    watchdog::start_watchdog(Duration::from_millis(1));
+
     reader_blocks_writer();
     writer_blocks_reader();
+    locker_blocks_locker();
 }
 
 fn reader_blocks_writer() {
@@ -41,7 +49,10 @@ fn deep2_init() -> Arc<RwLockWrapped<HashMap<i32, i32>>> {
 }
 
 fn writer_blocks_reader() {
-    let lock: Arc<RwLockWrapped<HashMap<i32, i32>>> = Arc::new(RwLockWrapped::new(HashMap::new()));
+    let lock: Arc<RwLockWrapped<HashMap<i32, i32>>> = Arc::new(RwLockWrapped::new_named(
+        "writer_blocks_reader",
+        HashMap::new(),
+    ));
 
     let l1 = lock.clone();
     let thread = thread::spawn(move || {
@@ -59,3 +70,23 @@ fn writer_blocks_reader() {
 
     thread.join().unwrap();
 }
+
+fn locker_blocks_locker() {
+    let lock: Arc<MutexWrapped<HashMap<i32, i32>>> = Arc::new(MutexWrapped::new(HashMap::new()));
+
+    let l1 = lock.clone();
+    let thread = thread::spawn(move || {
+        let m1 = l1.lock().unwrap();
+        println!("acquire mutex lock {} ...", m1.len());
+        thread::sleep(Duration::from_millis(500));
+        println!("... release mutex lock.");
+    });
+    // wait unit m1 lock is acquired
+    thread::sleep(Duration::from_millis(50));
+
+    println!("acquiring mutex lock2 ...");
+    let _mutex_lock = lock.lock().unwrap();
+    println!("... release mutex lock2.");
+
+    thread.join().unwrap();
+}
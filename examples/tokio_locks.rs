@@ -0,0 +1,52 @@
+use env_logger::Env;
+use rust_debugging_locks::tokio_locks::{TokioMutexWrapped, TokioRwLockWrapped};
+use std::sync::Arc;
+use std::time::Duration;
+
+// cargo run --example tokio_locks --features tokio
+#[tokio::main(flavor = "multi_thread", worker_threads = 2)]
+async fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
+
+    writer_blocks_reader().await;
+    locker_blocks_locker().await;
+}
+
+async fn writer_blocks_reader() {
+    let lock = Arc::new(TokioRwLockWrapped::new(vec![1, 2, 3]));
+
+    let l1 = lock.clone();
+    let task = tokio::spawn(async move {
+        let mut w1 = l1.write().await;
+        println!("acquire write lock ...");
+        w1.push(4);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        println!("... release write lock.");
+    });
+    // wait until w1 lock is acquired
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    println!("acquiring reader lock ...");
+    let reader_lock = lock.read().await;
+    println!("... reader lock acquired: {:?}", *reader_lock);
+
+    task.await.unwrap();
+}
+
+async fn locker_blocks_locker() {
+    let mutex = Arc::new(TokioMutexWrapped::new(0));
+
+    let m1 = mutex.clone();
+    let task = tokio::spawn(async move {
+        let mut l1 = m1.lock().await;
+        *l1 += 1;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    println!("acquiring mutex ...");
+    let value = *mutex.lock().await;
+    println!("... mutex acquired: {}", value);
+
+    task.await.unwrap();
+}
@@ -0,0 +1,404 @@
+use crate::lock_events::{dispatch_deadlock_event, lock_label, LockAccess};
+use crate::lock_registry::{all_locks, LockId};
+use crate::stacktrace_util::{Stracktrace, ThreadInfo, UnresolvedStracktrace};
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Display;
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::ThreadId;
+use std::time::Duration;
+
+// wait-for graph: thread A waits for lock L which is held by thread B => edge A -> B
+// a cycle in that graph is a deadlock
+
+/// One thread of a deadlock cycle.
+pub struct DeadlockParticipant {
+    pub thread: Arc<ThreadInfo>,
+    // locktag of the lock this thread is waiting for
+    pub waiting_for: String,
+    // name of that lock
+    pub name: Option<&'static str>,
+    pub access: LockAccess,
+    // caller of read/write/lock of this thread and of new of the lock
+    pub location_waiting: &'static Location<'static>,
+    pub created_at: &'static Location<'static>,
+    pub stack_waiting: Option<Arc<UnresolvedStracktrace>>,
+    // where the next thread in the cycle acquired the lock we are waiting for
+    pub stack_holder: Option<Arc<UnresolvedStracktrace>>,
+    pub stack_created: Option<Arc<Stracktrace>>,
+}
+
+/// Cycle of threads where each thread waits for a lock held by the next thread.
+pub struct DeadlockEvent {
+    pub participants: Vec<DeadlockParticipant>,
+}
+
+impl Display for DeadlockEvent {
+    // e.g. "DEADLOCK: main:ThreadId(1) -[xFxiD]-> worker:ThreadId(2) -[JuCPL]-> main:ThreadId(1)"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DEADLOCK:")?;
+        for participant in &self.participants {
+            write!(
+                f,
+                " {} -[{}]->",
+                participant.thread,
+                lock_label(&participant.waiting_for, participant.name)
+            )?;
+        }
+        match self.participants.first() {
+            Some(first) => write!(f, " {}", first.thread),
+            None => Ok(()),
+        }
+    }
+}
+
+struct WaitingThread {
+    thread: Arc<ThreadInfo>,
+    lock_id: LockId,
+    access: LockAccess,
+    location: &'static Location<'static>,
+    stack: Option<Arc<UnresolvedStracktrace>>,
+}
+
+struct HoldingThread {
+    thread_id: ThreadId,
+    access: LockAccess,
+    stack: Option<Arc<UnresolvedStracktrace>>,
+}
+
+struct LockInfo {
+    locktag: String,
+    name: Option<&'static str>,
+    created_at: &'static Location<'static>,
+    stack_created: Option<Arc<Stracktrace>>,
+}
+
+#[derive(Default)]
+struct WaitForGraph {
+    waiting: HashMap<ThreadId, WaitingThread>,
+    holders: HashMap<LockId, Vec<HoldingThread>>,
+    locks: HashMap<LockId, LockInfo>,
+}
+
+impl WaitForGraph {
+    fn from_registry() -> WaitForGraph {
+        let mut graph = WaitForGraph::default();
+        for lock in all_locks() {
+            // a waiting task does not block its worker thread
+            for waiter in lock.waiters().into_iter().filter(|waiter| !waiter.task) {
+                graph.waiting.insert(
+                    waiter.thread.thread_id,
+                    WaitingThread {
+                        thread: waiter.thread,
+                        lock_id: lock.id,
+                        access: waiter.access,
+                        location: waiter.location,
+                        stack: waiter.stack,
+                    },
+                );
+            }
+            let holders = lock
+                .holders()
+                .into_iter()
+                .map(|holder| HoldingThread {
+                    thread_id: holder.thread.thread_id,
+                    access: holder.access,
+                    stack: holder.stack,
+                })
+                .collect();
+            graph.holders.insert(lock.id, holders);
+            graph.locks.insert(
+                lock.id,
+                LockInfo {
+                    locktag: lock.locktag().to_string(),
+                    name: lock.name,
+                    created_at: lock.created_at,
+                    stack_created: lock.stack_created.clone(),
+                },
+            );
+        }
+        graph
+    }
+
+    fn successors(&self, thread_id: &ThreadId) -> Vec<ThreadId> {
+        match self.waiting.get(thread_id) {
+            None => vec![],
+            Some(waiting) => match self.holders.get(&waiting.lock_id) {
+                None => vec![],
+                Some(holders) => {
+                    // a thread might hold the same lock more than once (e.g. recursive read)
+                    let mut successors: Vec<ThreadId> = vec![];
+                    for holder in holders {
+                        // upgrading waits for the other readers only
+                        let upgrading = holder.thread_id == *thread_id
+                            && holder.access == LockAccess::UpgradableRead;
+                        if !upgrading && !successors.contains(&holder.thread_id) {
+                            successors.push(holder.thread_id);
+                        }
+                    }
+                    successors
+                }
+            },
+        }
+    }
+
+    // returns each elementary cycle once; ordering of each cycle follows the wait-for edges
+    // each cycle is searched from its smallest thread (see thread_order) through larger threads only -
+    // thus different cycles over the same threads are found separately
+    fn find_cycles(&self) -> Vec<Vec<ThreadId>> {
+        let mut starts: Vec<ThreadId> = self.waiting.keys().copied().collect();
+        starts.sort_by_key(thread_order);
+
+        let mut cycles = vec![];
+        for start in &starts {
+            let mut path: Vec<ThreadId> = vec![];
+            self.visit(*start, *start, &mut path, &mut cycles);
+        }
+        cycles
+    }
+
+    fn visit(
+        &self,
+        start: ThreadId,
+        current: ThreadId,
+        path: &mut Vec<ThreadId>,
+        cycles: &mut Vec<Vec<ThreadId>>,
+    ) {
+        path.push(current);
+        for next in self.successors(&current) {
+            if next == start {
+                cycles.push(path.clone());
+            } else if thread_order(&next) > thread_order(&start) && !path.contains(&next) {
+                self.visit(start, next, path, cycles);
+            }
+        }
+        path.pop();
+    }
+
+    fn to_event(&self, cycle: &[ThreadId]) -> DeadlockEvent {
+        let participants = cycle
+            .iter()
+            .enumerate()
+            .map(|(idx, thread_id)| {
+                let waiting = &self.waiting[thread_id];
+                let next_thread_id = cycle[(idx + 1) % cycle.len()];
+                let lock = &self.locks[&waiting.lock_id];
+                let stack_holder = self.holders[&waiting.lock_id]
+                    .iter()
+                    .find(|holder| holder.thread_id == next_thread_id)
+                    .and_then(|holder| holder.stack.clone());
+                DeadlockParticipant {
+                    thread: waiting.thread.clone(),
+                    waiting_for: lock.locktag.clone(),
+                    name: lock.name,
+                    access: waiting.access,
+                    location_waiting: waiting.location,
+                    created_at: lock.created_at,
+                    stack_waiting: waiting.stack.clone(),
+                    stack_holder,
+                    stack_created: lock.stack_created.clone(),
+                }
+            })
+            .collect();
+        DeadlockEvent { participants }
+    }
+}
+
+// total order of the threads which is stable across scans
+fn thread_order(thread_id: &ThreadId) -> String {
+    // ThreadId is not Ord; the debug format is unique ("ThreadId(42)"), pad for numeric order
+    let id = format!("{:?}", thread_id);
+    format!("{:>24}", id)
+}
+
+// identifies a deadlock independent of the thread we started the search from:
+// the cycle rotated to start at its smallest thread
+fn cycle_key(cycle: &[ThreadId]) -> Vec<String> {
+    let key: Vec<String> = cycle.iter().map(thread_order).collect();
+    let first = key
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.cmp(b))
+        .map(|(idx, _)| idx)
+        .unwrap_or(0);
+    key[first..].iter().chain(&key[..first]).cloned().collect()
+}
+
+/// Inspect all wrapped locks and return the deadlocks which exist right now.
+///
+/// The locks are not inspected atomically: a cycle may consist of a holder which got released
+/// meanwhile. The background detector (see [`start_deadlock_detector`]) therefore only reports
+/// cycles seen in two consecutive scans.
+pub fn check_deadlocks() -> Vec<DeadlockEvent> {
+    let graph = WaitForGraph::from_registry();
+    graph
+        .find_cycles()
+        .iter()
+        .map(|cycle| graph.to_event(cycle))
+        .collect()
+}
+
+static DETECTOR_STARTED: AtomicBool = AtomicBool::new(false);
+static REPORTED: Mutex<Vec<Vec<String>>> = Mutex::new(Vec::new());
+// cycles of the previous scan; reported once confirmed by the next scan
+static CANDIDATES: Mutex<Vec<Vec<String>>> = Mutex::new(Vec::new());
+
+/// Start a background thread which checks for deadlocks every `interval`.
+///
+/// Each deadlock gets reported once to the global event handler.
+/// Calling this more than once has no effect.
+pub fn start_deadlock_detector(interval: Duration) {
+    if DETECTOR_STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    thread::Builder::new()
+        .name("deadlock-detector".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            report_new_deadlocks();
+        })
+        .expect("failed to spawn deadlock detector thread");
+}
+
+fn report_new_deadlocks() {
+    let graph = WaitForGraph::from_registry();
+    let cycles = graph.find_cycles();
+
+    let current: Vec<Vec<String>> = cycles.iter().map(|cycle| cycle_key(cycle)).collect();
+    let previous = std::mem::replace(&mut *CANDIDATES.lock().unwrap(), current.clone());
+
+    let mut reported = REPORTED.lock().unwrap();
+    // forget resolved deadlocks - e.g. when the same threads deadlock again later
+    reported.retain(|key| current.contains(key));
+
+    for (cycle, key) in cycles.iter().zip(current) {
+        if reported.contains(&key) || !previous.contains(&key) {
+            continue;
+        }
+        dispatch_deadlock_event(&graph.to_event(cycle));
+        reported.push(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn some_thread_id() -> ThreadId {
+        thread::spawn(|| thread::current().id()).join().unwrap()
+    }
+
+    fn waits_for(graph: &mut WaitForGraph, waiter: ThreadId, lock_id: LockId, holder: ThreadId) {
+        graph.waiting.insert(
+            waiter,
+            WaitingThread {
+                thread: Arc::new(ThreadInfo {
+                    thread_id: waiter,
+                    name: "test".to_string(),
+                }),
+                lock_id,
+                access: LockAccess::Write,
+                location: Location::caller(),
+                stack: None,
+            },
+        );
+        graph
+            .holders
+            .entry(lock_id)
+            .or_default()
+            .push(HoldingThread {
+                thread_id: holder,
+                access: LockAccess::Write,
+                stack: None,
+            });
+        graph.locks.insert(
+            lock_id,
+            LockInfo {
+                locktag: format!("L{}", lock_id),
+                name: None,
+                created_at: Location::caller(),
+                stack_created: None,
+            },
+        );
+    }
+
+    #[test]
+    fn upgrading_thread_waits_for_other_readers_only() {
+        let (a, b) = (some_thread_id(), some_thread_id());
+        let mut graph = WaitForGraph::default();
+        // a upgrades its upgradable read while b holds a read
+        waits_for(&mut graph, a, 1, b);
+        let holders = graph.holders.get_mut(&1).unwrap();
+        holders[0].access = LockAccess::Read;
+        holders.push(HoldingThread {
+            thread_id: a,
+            access: LockAccess::UpgradableRead,
+            stack: None,
+        });
+
+        assert_eq!(vec![b], graph.successors(&a));
+        assert!(graph.find_cycles().is_empty());
+    }
+
+    #[test]
+    fn detect_ab_ba_cycle() {
+        let (a, b, c) = (some_thread_id(), some_thread_id(), some_thread_id());
+        let mut graph = WaitForGraph::default();
+        waits_for(&mut graph, a, 1, b);
+        waits_for(&mut graph, b, 2, a);
+        // c is blocked but not part of the cycle
+        waits_for(&mut graph, c, 1, b);
+
+        let cycles = graph.find_cycles();
+        assert_eq!(1, cycles.len());
+        assert_eq!(cycle_key(&[a, b]), cycle_key(&cycles[0]));
+
+        let event = graph.to_event(&cycles[0]);
+        assert_eq!(2, event.participants.len());
+        assert!(event.to_string().starts_with("DEADLOCK: test:"));
+    }
+
+    #[test]
+    fn detect_cycles_over_the_same_threads() {
+        let (a, b, c) = (some_thread_id(), some_thread_id(), some_thread_id());
+        let mut graph = WaitForGraph::default();
+        // lock 1 is read by b and c
+        waits_for(&mut graph, a, 1, b);
+        graph.holders.get_mut(&1).unwrap().push(HoldingThread {
+            thread_id: c,
+            access: LockAccess::Read,
+            stack: None,
+        });
+        waits_for(&mut graph, b, 2, c);
+        waits_for(&mut graph, c, 3, a);
+
+        let mut keys: Vec<Vec<String>> = graph
+            .find_cycles()
+            .iter()
+            .map(|cycle| cycle_key(cycle))
+            .collect();
+        keys.sort();
+        let mut expected = vec![cycle_key(&[a, b, c]), cycle_key(&[a, c])];
+        expected.sort();
+        assert_eq!(expected, keys);
+    }
+
+    #[test]
+    fn cycle_key_ignores_rotation_only() {
+        let (a, b, c) = (some_thread_id(), some_thread_id(), some_thread_id());
+        assert_eq!(cycle_key(&[a, b, c]), cycle_key(&[c, a, b]));
+        assert_ne!(cycle_key(&[a, b, c]), cycle_key(&[a, c, b]));
+    }
+
+    #[test]
+    fn no_cycle_for_plain_contention() {
+        let (a, b) = (some_thread_id(), some_thread_id());
+        let mut graph = WaitForGraph::default();
+        waits_for(&mut graph, a, 1, b);
+
+        assert!(graph.find_cycles().is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+use crate::stacktrace_util::{
+    backtrack_frame, get_current_stracktrace_unresolved, BacktrackError, UnresolvedStracktrace,
+};
+use crate::thresholds_config::thresholds_config;
+use log::{info, warn};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+// without debug symbols stacktraces cannot be symbolized; the events still carry the
+// source locations (#[track_caller]) of new and read/write/lock
+
+/// What to do if the binary has no debug symbols (see [`self_check`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugSymbolsPolicy {
+    /// Log one warning; stacktraces are still captured (but cannot be resolved).
+    WarnOnce,
+    /// Log on level info and stop capturing stacktraces; events carry the source locations only.
+    LocationOnly,
+    /// Panic in [`self_check`] - i.e. at startup, on creation of the first lock or when diagnostics get
+    /// enabled; never on acquisition.
+    Panic,
+}
+
+impl FromStr for DebugSymbolsPolicy {
+    type Err = String;
+
+    // values of env variable DEBUGGING_LOCKS_DEBUG_SYMBOLS
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "warn" => Ok(DebugSymbolsPolicy::WarnOnce),
+            "location" => Ok(DebugSymbolsPolicy::LocationOnly),
+            "panic" => Ok(DebugSymbolsPolicy::Panic),
+            _ => Err(format!("expected warn, location or panic: {:?}", value)),
+        }
+    }
+}
+
+static AVAILABLE: OnceLock<bool> = OnceLock::new();
+// set by DebugSymbolsPolicy::LocationOnly
+static STACKTRACES_DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Check once whether stacktraces can be symbolized and apply [`crate::thresholds_config::ThresholdsConfig::debug_symbols_policy`].
+///
+/// Runs implicitly on creation of the first wrapped lock and in
+/// [`crate::debugging_locks::set_diagnostics_enabled`]; call it at startup to fail fast.
+/// Subsequent calls return the cached result.
+pub fn self_check() -> Result<(), BacktrackError> {
+    let available = *AVAILABLE.get_or_init(|| {
+        let available = !matches!(
+            backtrack_frame(|_| false),
+            Err(BacktrackError::NoDebugSymbols)
+        );
+        if !available {
+            apply_policy(thresholds_config().debug_symbols_policy);
+        }
+        available
+    });
+    if available {
+        Ok(())
+    } else {
+        Err(BacktrackError::NoDebugSymbols)
+    }
+}
+
+fn apply_policy(policy: DebugSymbolsPolicy) {
+    match policy {
+        DebugSymbolsPolicy::WarnOnce => warn!(
+            "No debug symbols - stacktraces are not available, reporting source locations only (add debug = true to the profile)"
+        ),
+        DebugSymbolsPolicy::LocationOnly => {
+            info!("No debug symbols - stacktraces disabled, reporting source locations only");
+            STACKTRACES_DISABLED.store(true, Ordering::Relaxed);
+        }
+        DebugSymbolsPolicy::Panic => {
+            panic!("No debug symbols - rust_debugging_locks requires debug = true in the profile")
+        }
+    }
+}
+
+pub fn debug_symbols_available() -> bool {
+    self_check().is_ok()
+}
+
+// result of the self check if it ran already; the acquisition path must not run it (may panic)
+pub(crate) fn debug_symbols_missing() -> bool {
+    AVAILABLE.get() == Some(&false)
+}
+
+pub(crate) fn stacktraces_enabled() -> bool {
+    !STACKTRACES_DISABLED.load(Ordering::Relaxed)
+}
+
+/// Number of stacks of the acquisition path which are not available by error kind (see [`stack_capture_failures`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StackCaptureFailures {
+    pub no_start_frame: u64,
+    pub no_debug_symbols: u64,
+}
+
+static NO_START_FRAME_FAILURES: AtomicU64 = AtomicU64::new(0);
+static NO_DEBUG_SYMBOLS_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// Counted where the failure happens: on capture (e.g. no debug symbols) or on symbolization of a
+/// captured stack - once per stack, the result is cached. Failures are not fatal: the guard is handed
+/// back and the holder records the error kind instead of the stack.
+pub fn stack_capture_failures() -> StackCaptureFailures {
+    StackCaptureFailures {
+        no_start_frame: NO_START_FRAME_FAILURES.load(Ordering::Relaxed),
+        no_debug_symbols: NO_DEBUG_SYMBOLS_FAILURES.load(Ordering::Relaxed),
+    }
+}
+
+pub(crate) fn record_capture_failure(error: BacktrackError) {
+    let counter = match error {
+        BacktrackError::NoStartFrame => &NO_START_FRAME_FAILURES,
+        BacktrackError::NoDebugSymbols => &NO_DEBUG_SYMBOLS_FAILURES,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+// stack of the acquiring (or waiting) caller; never panics
+// - no debug symbols: counted here as the stack cannot be resolved anyway (see UnresolvedStracktrace::resolve)
+// - disabled by DebugSymbolsPolicy::LocationOnly: NoDebugSymbols
+pub(crate) fn capture_caller_stack() -> Result<Arc<UnresolvedStracktrace>, BacktrackError> {
+    if debug_symbols_missing() {
+        record_capture_failure(BacktrackError::NoDebugSymbols);
+    }
+    if !stacktraces_enabled() {
+        return Err(BacktrackError::NoDebugSymbols);
+    }
+    get_current_stracktrace_unresolved()
+        .map(Arc::new)
+        .inspect_err(|error| record_capture_failure(*error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_from_env_value() {
+        assert_eq!(Ok(DebugSymbolsPolicy::WarnOnce), "warn".parse());
+        assert_eq!(Ok(DebugSymbolsPolicy::LocationOnly), "location".parse());
+        assert_eq!(Ok(DebugSymbolsPolicy::Panic), "panic".parse());
+        assert!("fail".parse::<DebugSymbolsPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_binary_has_debug_symbols() {
+        assert!(self_check().is_ok());
+        assert!(stacktraces_enabled());
+    }
+}
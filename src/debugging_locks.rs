@@ -1,23 +1,30 @@
-use crate::stacktrace_util::{backtrack_frame, BacktrackError, Frame, Stracktrace, ThreadInfo};
-use crate::thresholds_config;
+use crate::debug_symbols;
+use crate::debug_symbols::capture_caller_stack;
+use crate::lock_events::{LockAccess, LockEventHandler};
+use crate::lock_order;
+use crate::lock_registry::{HolderToken, LockState, WaiterToken};
+use crate::lock_timeout::{LockTimeout, TryLockForError, TryLockForResult};
+use crate::stacktrace_util::{get_current_stracktrace, BacktrackError, Stracktrace};
+use crate::watchdog;
 use core::fmt;
-use log::{debug, info, warn};
+use log::{info, warn};
 use serde::ser::Error;
 use serde::{Serialize, Serializer};
-use std::cell::{Cell, RefCell};
-use std::sync::atomic::AtomicPtr;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{
-    Arc, LockResult, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
-    TryLockResult,
+    Arc, LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    TryLockError, TryLockResult,
 };
+use std::thread;
 use std::time::{Duration, Instant};
-use std::{ptr, thread};
 
 // newtype pattern
 pub struct RwLockWrapped<T: ?Sized> {
-    stack_created: Option<Stracktrace>,
-    // note: this does NOT reflect a currently acquired lock
-    last_returned_lock_from: Arc<Mutex<Option<Stracktrace>>>,
+    // creation stack, current holders and waiters
+    state: Arc<LockState>,
     // RwLock must be last element in struct
     inner: RwLock<T>,
 }
@@ -48,45 +55,196 @@ impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockWrapped<T> {
     }
 }
 
-const LIB_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub(crate) const LIB_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// runtime switch - see set_diagnostics_enabled
+static DIAGNOSTICS_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Turn the diagnostics of all wrapped locks (std, parking_lot and tokio) on or off at runtime.
+///
+/// While disabled, `read`/`write`/`lock` forward to the wrapped lock: no stacktraces, no polling, no events,
+/// no statistics. Locks created while disabled have no creation stack (the locktag is derived from the
+/// creation location) and are invisible to the watchdog and the deadlock detector.
+/// Enabling runs [`debug_symbols::self_check`], i.e. applies the debug symbols policy here and not on
+/// a later acquisition.
+pub fn set_diagnostics_enabled(enabled: bool) {
+    DIAGNOSTICS_ENABLED.store(enabled, Ordering::Relaxed);
+    if diagnostics_enabled() {
+        let _ = debug_symbols::self_check();
+    }
+}
+
+/// Always false with feature `passthrough`.
+pub fn diagnostics_enabled() -> bool {
+    !cfg!(feature = "passthrough") && DIAGNOSTICS_ENABLED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn stack_created_if_enabled() -> Option<Stracktrace> {
+    if diagnostics_enabled() {
+        capture_stack_created()
+    } else {
+        None
+    }
+}
+
+fn map_lock_result<G, W>(result: LockResult<G>, wrap: impl FnOnce(G) -> W) -> LockResult<W> {
+    match result {
+        Ok(guard) => Ok(wrap(guard)),
+        Err(poison) => Err(PoisonError::new(wrap(poison.into_inner()))),
+    }
+}
+
+// creation stack of a wrapped lock; also used to derive the locktag
+pub(crate) fn capture_stack_created() -> Option<Stracktrace> {
+    // applies the debug symbols policy on first use
+    let _ = debug_symbols::self_check();
+    if !debug_symbols::stacktraces_enabled() {
+        return None;
+    }
+    match get_current_stracktrace() {
+        Ok(stracktrace) => Some(stracktrace),
+        // reported once by the self check
+        Err(BacktrackError::NoDebugSymbols) => None,
+        Err(backtrack_error) => {
+            warn!(
+                "Unable to determine stacktrace - continue without! (error: {})",
+                backtrack_error
+            );
+            None
+        }
+    }
+}
 
 impl<T> RwLockWrapped<T> {
+    #[track_caller]
     pub fn new(t: T) -> RwLockWrapped<T> {
-        info!("NEW WRAPPED RWLOCK (v{})", LIB_VERSION);
-        return match get_current_stracktrace() {
-            Ok(stracktrace) => RwLockWrapped {
-                inner: RwLock::new(t),
-                stack_created: Option::from(stracktrace),
-                last_returned_lock_from: Arc::new(Mutex::new(None)),
-            },
-            Err(backtrack_error) => {
-                warn!(
-                    "Unable to determine stacktrace - continue without! (error: {})",
-                    backtrack_error
-                );
-                RwLockWrapped {
-                    inner: RwLock::new(t),
-                    stack_created: None,
-                    last_returned_lock_from: Arc::new(Mutex::new(None)),
-                }
-            }
-        };
+        RwLockWrapped::create(t, None)
+    }
+
+    /// Like [`RwLockWrapped::new`]; `name` is shown next to the locktag in all events and
+    /// selects the config of [`crate::thresholds_config::set_named_thresholds_config`].
+    #[track_caller]
+    pub fn new_named(name: &'static str, t: T) -> RwLockWrapped<T> {
+        RwLockWrapped::create(t, Some(name))
+    }
+
+    #[track_caller]
+    fn create(t: T, name: Option<&'static str>) -> RwLockWrapped<T> {
+        info!(
+            "NEW WRAPPED RWLOCK {}(v{})",
+            name.map(|name| format!("{} ", name)).unwrap_or_default(),
+            LIB_VERSION
+        );
+        RwLockWrapped {
+            inner: RwLock::new(t),
+            state: LockState::new(stack_created_if_enabled(), name, Location::caller()),
+        }
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        self.state.name
+    }
+
+    /// Dispatch events of this lock to `handler` instead of the global event handler.
+    pub fn with_event_handler(self, handler: Arc<dyn LockEventHandler>) -> RwLockWrapped<T> {
+        self.state.set_event_handler(handler);
+        self
     }
 
     pub fn to_rwlock(&self) -> &RwLock<T> {
         &self.inner
     }
 
-    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
-        write_smart(&self)
+    #[track_caller]
+    pub fn write(&self) -> LockResult<RwLockWriteGuardWrapped<'_, T>> {
+        if !diagnostics_enabled() {
+            return map_lock_result(self.inner.write(), |guard| RwLockWriteGuardWrapped {
+                holder: None,
+                inner: ManuallyDrop::new(guard),
+            });
+        }
+        write_smart(self)
+    }
+
+    /// Never blocks; a successful acquisition is tracked as holder, `WouldBlock` is counted
+    /// (see [`crate::lock_registry::would_block_counts`]).
+    #[track_caller]
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuardWrapped<'_, T>> {
+        try_acquired(
+            &self.state,
+            LockAccess::Read,
+            Location::caller(),
+            self.inner.try_read(),
+            |holder, guard| RwLockReadGuardWrapped {
+                holder,
+                inner: ManuallyDrop::new(guard),
+            },
+        )
     }
 
-    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
-        self.inner.try_read()
+    /// Like [`RwLockWrapped::try_read`].
+    #[track_caller]
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuardWrapped<'_, T>> {
+        try_acquired(
+            &self.state,
+            LockAccess::Write,
+            Location::caller(),
+            self.inner.try_write(),
+            |holder, guard| RwLockWriteGuardWrapped {
+                holder,
+                inner: ManuallyDrop::new(guard),
+            },
+        )
     }
 
-    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
-        read_smart(&self)
+    /// Like [`RwLockWrapped::read`] but gives up after `timeout`; the [`LockTimeout`] tells
+    /// who held the lock and where it was created.
+    #[track_caller]
+    pub fn try_read_for(
+        &self,
+        timeout: Duration,
+    ) -> TryLockForResult<RwLockReadGuardWrapped<'_, T>> {
+        acquire_for(
+            &self.state,
+            LockAccess::Read,
+            Location::caller(),
+            timeout,
+            || self.inner.try_read(),
+            |holder, guard| RwLockReadGuardWrapped {
+                holder,
+                inner: ManuallyDrop::new(guard),
+            },
+        )
+    }
+
+    /// Like [`RwLockWrapped::write`] but gives up after `timeout` (see [`RwLockWrapped::try_read_for`]).
+    #[track_caller]
+    pub fn try_write_for(
+        &self,
+        timeout: Duration,
+    ) -> TryLockForResult<RwLockWriteGuardWrapped<'_, T>> {
+        acquire_for(
+            &self.state,
+            LockAccess::Write,
+            Location::caller(),
+            timeout,
+            || self.inner.try_write(),
+            |holder, guard| RwLockWriteGuardWrapped {
+                holder,
+                inner: ManuallyDrop::new(guard),
+            },
+        )
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> LockResult<RwLockReadGuardWrapped<'_, T>> {
+        if !diagnostics_enabled() {
+            return map_lock_result(self.inner.read(), |guard| RwLockReadGuardWrapped {
+                holder: None,
+                inner: ManuallyDrop::new(guard),
+            });
+        }
+        read_smart(self)
     }
 
     pub fn into_inner(self) -> LockResult<T> {
@@ -96,231 +254,560 @@ impl<T> RwLockWrapped<T> {
 
 impl<T: Default> Default for RwLockWrapped<T> {
     /// Creates a new `RwLock<T>`, with the `Default` value for T.
+    #[track_caller]
     fn default() -> RwLockWrapped<T> {
         RwLockWrapped::new(Default::default())
     }
 }
 
-// impl<T: ?Sized> Deref for RwLockWrapped<T> {
-//     type Target = RwLock<T>;
-//
-//     fn deref(&self) -> &Self::Target {
-//         &self.0
-//     }
-// }
-
-fn write_smart<T>(rwlock_wrapped: &RwLockWrapped<T>) -> LockResult<RwLockWriteGuard<'_, T>> {
-    let rwlock = &rwlock_wrapped.inner;
-    let mut last_returned = &rwlock_wrapped.last_returned_lock_from;
+// newtype pattern
+pub struct MutexWrapped<T: ?Sized> {
+    // creation stack, current holders and waiters
+    state: Arc<LockState>,
+    // Mutex must be last element in struct
+    inner: Mutex<T>,
+}
 
-    let mut cnt: u64 = 0;
-    // consider using SystemTime here
-    let wait_since = Instant::now();
-    loop {
-        match rwlock.try_write() {
-            Ok(guard) => {
-                let stack_caller = get_current_stracktrace();
-                *last_returned.lock().unwrap() =
-                    Some(stack_caller.expect("stacktrace should be available"));
-                return Ok(guard);
-            }
-            Err(err) => {
-                match err {
-                    TryLockError::Poisoned(poison) => {
-                        return Err(poison);
-                    }
-                    TryLockError::WouldBlock => {
-                        let waittime_elapsed = wait_since.elapsed();
-                        if thresholds_config::should_inspect_lock(cnt) {
-                            let stack_caller = get_current_stracktrace();
-                            let thread = thread::current();
-                            let thread_info = ThreadInfo {
-                                thread_id: thread.id(),
-                                name: thread.name().unwrap_or("no_thread").to_string(),
-                            };
-                            let stacktrace_created = &rwlock_wrapped.stack_created;
-                            let last_lock_from = &rwlock_wrapped.last_returned_lock_from;
-
-                            // dispatch to custom handle
-                            // note: implementation must deal with debounce, etc.
-                            handle_blocked_writer_event(
-                                wait_since,
-                                waittime_elapsed,
-                                cnt,
-                                thread_info,
-                                stacktrace_created.clone(),
-                                last_lock_from.clone(),
-                                &stack_caller.ok(),
-                            );
-                        }
-
-                        thresholds_config::sleep_backoff(cnt);
-                        cnt += 1;
-                    }
-                }
-            }
+impl<T: ?Sized> Serialize for MutexWrapped<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self.inner.lock() {
+            Ok(locked) => locked.serialize(serializer),
+            Err(_) => Err(Error::custom("lock poison error while serializing")),
         }
     }
 }
 
-fn read_smart<T>(rwlock_wrapped: &RwLockWrapped<T>) -> LockResult<RwLockReadGuard<'_, T>> {
-    let rwlock = &rwlock_wrapped.inner;
-    let mut last_returned = &rwlock_wrapped.last_returned_lock_from;
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MutexWrapped<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
 
-    let mut cnt: u64 = 0;
-    // consider using SystemTime here
-    let wait_since = Instant::now();
-    loop {
-        match rwlock.try_read() {
-            Ok(guard) => {
-                let stack_caller = get_current_stracktrace();
-                *last_returned.lock().unwrap() =
-                    Some(stack_caller.expect("stacktrace should be available"));
-                return Ok(guard);
-            }
-            Err(err) => {
-                match err {
-                    TryLockError::Poisoned(poison) => {
-                        return Err(poison);
-                    }
-                    TryLockError::WouldBlock => {
-                        let waittime_elapsed = wait_since.elapsed();
-                        if thresholds_config::should_inspect_lock(cnt) {
-                            let stack_caller = get_current_stracktrace();
-                            let thread = thread::current();
-                            let thread_info = ThreadInfo {
-                                thread_id: thread.id(),
-                                name: thread.name().unwrap_or("no_thread").to_string(),
-                            };
-                            let stacktrace_created = &rwlock_wrapped.stack_created;
-                            let last_lock_from = &rwlock_wrapped.last_returned_lock_from;
-
-                            // dispatch to custom handle
-                            // note: implementation must deal with debounce, etc.
-                            handle_blocked_reader_event(
-                                wait_since,
-                                waittime_elapsed,
-                                cnt,
-                                thread_info,
-                                stacktrace_created.clone(),
-                                last_lock_from.clone(),
-                                &stack_caller.ok(),
-                            );
-                        }
-
-                        thresholds_config::sleep_backoff(cnt);
-                        cnt += 1;
-                    }
-                }
-            }
+impl<T> MutexWrapped<T> {
+    #[track_caller]
+    pub fn new(t: T) -> MutexWrapped<T> {
+        MutexWrapped::create(t, None)
+    }
+
+    /// See [`RwLockWrapped::new_named`].
+    #[track_caller]
+    pub fn new_named(name: &'static str, t: T) -> MutexWrapped<T> {
+        MutexWrapped::create(t, Some(name))
+    }
+
+    #[track_caller]
+    fn create(t: T, name: Option<&'static str>) -> MutexWrapped<T> {
+        info!(
+            "NEW WRAPPED MUTEX {}(v{})",
+            name.map(|name| format!("{} ", name)).unwrap_or_default(),
+            LIB_VERSION
+        );
+        MutexWrapped {
+            inner: Mutex::new(t),
+            state: LockState::new(stack_created_if_enabled(), name, Location::caller()),
         }
     }
+
+    pub fn name(&self) -> Option<&'static str> {
+        self.state.name
+    }
+
+    /// See [`RwLockWrapped::with_event_handler`].
+    pub fn with_event_handler(self, handler: Arc<dyn LockEventHandler>) -> MutexWrapped<T> {
+        self.state.set_event_handler(handler);
+        self
+    }
+
+    pub fn to_mutex(&self) -> &Mutex<T> {
+        &self.inner
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> LockResult<MutexGuardWrapped<'_, T>> {
+        if !diagnostics_enabled() {
+            return map_lock_result(self.inner.lock(), |guard| MutexGuardWrapped {
+                holder: None,
+                inner: ManuallyDrop::new(guard),
+            });
+        }
+        lock_smart(self)
+    }
+
+    /// Like [`RwLockWrapped::try_read`].
+    #[track_caller]
+    pub fn try_lock(&self) -> TryLockResult<MutexGuardWrapped<'_, T>> {
+        try_acquired(
+            &self.state,
+            LockAccess::Lock,
+            Location::caller(),
+            self.inner.try_lock(),
+            |holder, guard| MutexGuardWrapped {
+                holder,
+                inner: ManuallyDrop::new(guard),
+            },
+        )
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
 }
 
-// custom handling
-// TODO discuss "&Option" vs "Option"
-fn handle_blocked_writer_event(
-    _since: Instant,
-    elapsed: Duration,
-    cnt: u64,
-    thread: ThreadInfo,
-    stacktrace_created: &Option<Stracktrace>,
-    last_returned_lock_from: Arc<Mutex<Option<Stracktrace>>>,
-    stacktrace_caller: &Option<Stracktrace>,
-) {
-    let locktag = get_lock_identifier(stacktrace_created);
+impl<T: Default> Default for MutexWrapped<T> {
+    /// Creates a new `Mutex<T>`, with the `Default` value for T.
+    #[track_caller]
+    fn default() -> MutexWrapped<T> {
+        MutexWrapped::new(Default::default())
+    }
+}
 
-    info!(
-        "WRITER BLOCKED on thread {} for {:?} (locktag {})",
-        thread, elapsed, locktag
-    );
+/// RAII guard of [`RwLockWrapped::read`]; the holder is tracked until the guard is dropped.
+pub struct RwLockReadGuardWrapped<'a, T: ?Sized> {
+    // None in passthrough mode - see diagnostics_enabled
+    holder: Option<HolderToken<'a>>,
+    // dropped explicitly - see Drop
+    inner: ManuallyDrop<RwLockReadGuard<'a, T>>,
+}
+
+/// RAII guard of [`RwLockWrapped::write`]; the holder is tracked until the guard is dropped.
+pub struct RwLockWriteGuardWrapped<'a, T: ?Sized> {
+    // None in passthrough mode - see diagnostics_enabled
+    holder: Option<HolderToken<'a>>,
+    // dropped explicitly - see Drop
+    inner: ManuallyDrop<RwLockWriteGuard<'a, T>>,
+}
+
+/// RAII guard of [`MutexWrapped::lock`]; the holder is tracked until the guard is dropped.
+pub struct MutexGuardWrapped<'a, T: ?Sized> {
+    // None in passthrough mode - see diagnostics_enabled
+    holder: Option<HolderToken<'a>>,
+    // dropped explicitly - see Drop
+    inner: ManuallyDrop<MutexGuard<'a, T>>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuardWrapped<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner.deref()
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuardWrapped<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner.deref()
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuardWrapped<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner.deref_mut()
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuardWrapped<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner.deref()
+    }
+}
 
-    match stacktrace_caller {
-        None => {}
-        Some(stacktrace) => {
-            log_frames("blocking call", locktag, &stacktrace);
+impl<T: ?Sized> DerefMut for MutexGuardWrapped<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner.deref_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuardWrapped<'_, T> {
+    fn drop(&mut self) {
+        let inner = &mut self.inner;
+        // SAFETY: inner is not accessed after this point
+        match &mut self.holder {
+            Some(holder) => holder.release(|| unsafe { ManuallyDrop::drop(inner) }),
+            None => unsafe { ManuallyDrop::drop(inner) },
         }
     }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockReadGuardWrapped<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
 
-    match last_returned_lock_from.lock().unwrap().as_ref() {
-        None => {}
-        Some(stacktrace) => {
-            log_frames("current lock holder", locktag, &stacktrace);
+impl<T: ?Sized> Drop for RwLockWriteGuardWrapped<'_, T> {
+    fn drop(&mut self) {
+        let inner = &mut self.inner;
+        // SAFETY: inner is not accessed after this point
+        match &mut self.holder {
+            Some(holder) => {
+                record_poisoning(holder);
+                holder.release(|| unsafe { ManuallyDrop::drop(inner) })
+            }
+            None => unsafe { ManuallyDrop::drop(inner) },
         }
     }
+}
 
-    match stacktrace_created {
-        None => {}
-        Some(stacktrace) => {
-            log_frames("rwlock constructed here", locktag, &stacktrace);
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockWriteGuardWrapped<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuardWrapped<'_, T> {
+    fn drop(&mut self) {
+        let inner = &mut self.inner;
+        // SAFETY: inner is not accessed after this point
+        match &mut self.holder {
+            Some(holder) => {
+                record_poisoning(holder);
+                holder.release(|| unsafe { ManuallyDrop::drop(inner) })
+            }
+            None => unsafe { ManuallyDrop::drop(inner) },
         }
     }
 }
 
-fn handle_blocked_reader_event(
-    _since: Instant,
-    elapsed: Duration,
-    cnt: u64,
-    thread: ThreadInfo,
-    stacktrace_created: &Option<Stracktrace>,
-    last_returned_lock_from: Arc<Mutex<Option<Stracktrace>>>,
-    stacktrace_caller: &Option<Stracktrace>,
-) {
-    let locktag = get_lock_identifier(stacktrace_created);
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MutexGuardWrapped<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+// impl<T: ?Sized> Deref for RwLockWrapped<T> {
+//     type Target = RwLock<T>;
+//
+//     fn deref(&self) -> &Self::Target {
+//         &self.0
+//     }
+// }
+
+#[track_caller]
+fn write_smart<T>(rwlock_wrapped: &RwLockWrapped<T>) -> LockResult<RwLockWriteGuardWrapped<'_, T>> {
+    let rwlock = &rwlock_wrapped.inner;
+    let state = &rwlock_wrapped.state;
+    let location = Location::caller();
+    lock_order::before_acquire(state, location);
+
+    let result = acquire_blocking(
+        state,
+        LockAccess::Write,
+        location,
+        || rwlock.try_write(),
+        || rwlock.write(),
+    );
+    wrap_acquired(
+        state,
+        LockAccess::Write,
+        location,
+        result,
+        |holder, guard| RwLockWriteGuardWrapped {
+            holder: Some(holder),
+            inner: ManuallyDrop::new(guard),
+        },
+    )
+}
+
+#[track_caller]
+fn read_smart<T>(rwlock_wrapped: &RwLockWrapped<T>) -> LockResult<RwLockReadGuardWrapped<'_, T>> {
+    let rwlock = &rwlock_wrapped.inner;
+    let state = &rwlock_wrapped.state;
+    let location = Location::caller();
+    lock_order::before_acquire(state, location);
+
+    let result = acquire_blocking(
+        state,
+        LockAccess::Read,
+        location,
+        || rwlock.try_read(),
+        || rwlock.read(),
+    );
+    wrap_acquired(
+        state,
+        LockAccess::Read,
+        location,
+        result,
+        |holder, guard| RwLockReadGuardWrapped {
+            holder: Some(holder),
+            inner: ManuallyDrop::new(guard),
+        },
+    )
+}
 
-    info!(
-        "READER BLOCKED on thread {} for {:?} (locktag {})",
-        thread, elapsed, locktag
+#[track_caller]
+fn lock_smart<T>(mutex_wrapped: &MutexWrapped<T>) -> LockResult<MutexGuardWrapped<'_, T>> {
+    let mutex = &mutex_wrapped.inner;
+    let state = &mutex_wrapped.state;
+    let location = Location::caller();
+    lock_order::before_acquire(state, location);
+
+    let result = acquire_blocking(
+        state,
+        LockAccess::Lock,
+        location,
+        || mutex.try_lock(),
+        || mutex.lock(),
     );
+    wrap_acquired(
+        state,
+        LockAccess::Lock,
+        location,
+        result,
+        |holder, guard| MutexGuardWrapped {
+            holder: Some(holder),
+            inner: ManuallyDrop::new(guard),
+        },
+    )
+}
 
-    match stacktrace_caller {
-        None => {}
-        Some(stacktrace) => {
-            log_frames("blocking call", locktag, &stacktrace);
-        }
+// blocks on the real lock if the lock is contended (keeps fairness and timing of the lock);
+// the watchdog reports the registered waiter meanwhile
+fn acquire_blocking<G>(
+    state: &LockState,
+    access: LockAccess,
+    location: &'static Location<'static>,
+    try_acquire: impl FnOnce() -> TryLockResult<G>,
+    acquire: impl FnOnce() -> LockResult<G>,
+) -> LockResult<G> {
+    match try_acquire() {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::Poisoned(poison)) => Err(poison),
+        Err(TryLockError::WouldBlock) => wait_registered(state, access, location, acquire),
     }
+}
 
-    match last_returned_lock_from.lock().unwrap().as_ref() {
-        None => {}
-        Some(stacktrace) => {
-            log_frames("current lock holder", locktag, &stacktrace);
+// registers the current thread as waiter while `wait` blocks
+pub(crate) fn wait_registered<R>(
+    state: &LockState,
+    access: LockAccess,
+    location: &'static Location<'static>,
+    wait: impl FnOnce() -> R,
+) -> R {
+    let stack_caller = capture_caller_stack();
+    // deregistered on return
+    let _waiter: WaiterToken = state.register_waiter(access, location, stack_caller);
+    watchdog::ensure_started();
+    wait()
+}
+
+// non-blocking acquisition: no waiter and no lock order check (cannot deadlock)
+fn try_acquired<'a, G, W>(
+    state: &'a LockState,
+    access: LockAccess,
+    location: &'static Location<'static>,
+    result: TryLockResult<G>,
+    wrap: impl FnOnce(Option<HolderToken<'a>>, G) -> W,
+) -> TryLockResult<W> {
+    let result = match result {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::Poisoned(poison)) => Err(poison),
+        Err(TryLockError::WouldBlock) => {
+            record_would_block(state);
+            return Err(TryLockError::WouldBlock);
         }
+    };
+    if !diagnostics_enabled() {
+        return map_lock_result(result, |guard| wrap(None, guard)).map_err(TryLockError::from);
+    }
+    wrap_acquired(state, access, location, result, |holder, guard| {
+        wrap(Some(holder), guard)
+    })
+    .map_err(TryLockError::from)
+}
+
+// counted while diagnostics are enabled only (like all other statistics)
+pub(crate) fn record_would_block(state: &LockState) {
+    if let Some(stats) = state.stats().filter(|_| diagnostics_enabled()) {
+        stats.record_would_block();
     }
+}
 
-    match stacktrace_created {
-        None => {}
-        Some(stacktrace) => {
-            log_frames("rwlock constructed here", locktag, &stacktrace);
+// upper bound of the sleep between two attempts of acquire_for
+const MAX_POLL_BACKOFF: Duration = Duration::from_millis(1);
+
+// std has no timed acquisition - poll with exponential backoff until the deadline;
+// the waiter is registered while polling, so the watchdog and deadlock detector see it
+fn acquire_for<'a, G, W>(
+    state: &'a LockState,
+    access: LockAccess,
+    location: &'static Location<'static>,
+    timeout: Duration,
+    mut try_acquire: impl FnMut() -> TryLockResult<G>,
+    wrap: impl FnOnce(Option<HolderToken<'a>>, G) -> W,
+) -> TryLockForResult<W> {
+    let polled = if !diagnostics_enabled() {
+        poll_until(timeout, try_acquire)
+    } else {
+        lock_order::before_acquire(state, location);
+        match try_acquire() {
+            Ok(guard) => Ok(Ok(guard)),
+            Err(TryLockError::Poisoned(poison)) => Ok(Err(poison)),
+            Err(TryLockError::WouldBlock) => {
+                wait_registered(state, access, location, || poll_until(timeout, try_acquire))
+            }
+        }
+    };
+
+    match polled {
+        Err(waited) => Err(TryLockForError::Timeout(LockTimeout::new(
+            state, access, waited, location,
+        ))),
+        Ok(result) if !diagnostics_enabled() => {
+            map_lock_result(result, |guard| wrap(None, guard)).map_err(TryLockForError::from)
         }
+        Ok(result) => wrap_acquired(state, access, location, result, |holder, guard| {
+            wrap(Some(holder), guard)
+        })
+        .map_err(TryLockForError::from),
     }
 }
 
-fn log_frames(msg: &str, locktag: &str, stacktrace: &&Stracktrace) {
-    debug!(" |{}>\t{}:", locktag, msg);
-    for frame in &stacktrace.frames {
-        debug!(
-            " |{}>\t  {}!{}:{}",
-            locktag, frame.filename, frame.method, frame.line_no
-        );
+// Err(waited) if the lock could not be acquired within timeout
+fn poll_until<G>(
+    timeout: Duration,
+    mut try_acquire: impl FnMut() -> TryLockResult<G>,
+) -> Result<LockResult<G>, Duration> {
+    let start = Instant::now();
+    let mut backoff = Duration::from_micros(10);
+    loop {
+        match try_acquire() {
+            Ok(guard) => return Ok(Ok(guard)),
+            Err(TryLockError::Poisoned(poison)) => return Ok(Err(poison)),
+            Err(TryLockError::WouldBlock) => {}
+        }
+        let waited = start.elapsed();
+        if waited >= timeout {
+            return Err(waited);
+        }
+        thread::sleep(backoff.min(timeout - waited));
+        backoff = (backoff * 2).min(MAX_POLL_BACKOFF);
     }
 }
 
-// e.g. "NFBZP"
-fn get_lock_identifier(stacktrace_created: &Option<Stracktrace>) -> &str {
-    match stacktrace_created {
-        None => "n/a",
-        Some(stacktrace) => &stacktrace.hash.as_ref(),
+// std poisons the lock if a write or mutex guard gets dropped while panicking
+fn record_poisoning(holder: &HolderToken) {
+    if thread::panicking() {
+        if let Some(stats) = holder.state().stats() {
+            stats.record_poisoned();
+        }
     }
 }
 
-fn get_current_stracktrace() -> Result<Stracktrace, BacktrackError> {
-    // covers:
-    // rust_debugging_locks::debugging_locks::
-    // rust_debugging_locks::stacktrace_util::
-    const OMIT_FRAME_SUFFIX1: &str = "rust_debugging_locks:";
-    // <rust_debugging_locks::debugging_locks::RwLockWrapped<T> as core::default::Default>::default::haed7701ba5f48aa2:97
-    const OMIT_FRAME_SUFFIX2: &str = "<rust_debugging_locks:";
-    backtrack_frame(|symbol_name| {
-        symbol_name.starts_with(OMIT_FRAME_SUFFIX1) || symbol_name.starts_with(OMIT_FRAME_SUFFIX2)
-    })
+// tracks the holder of the acquired (or poisoned) lock
+fn wrap_acquired<'a, G, W>(
+    state: &'a LockState,
+    access: LockAccess,
+    location: &'static Location<'static>,
+    result: LockResult<G>,
+    wrap: impl FnOnce(HolderToken<'a>, G) -> W,
+) -> LockResult<W> {
+    // a failed stack capture is recorded in the holder - the guard is handed back anyway
+    let holder = state.register_holder(access, location, capture_caller_stack());
+    map_lock_result(result, |guard| wrap(holder, guard))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug_symbols::stack_capture_failures;
+    use crate::lock_stats::lock_stats_by_locktag;
+    use crate::stacktrace_util::locktag_from_location;
+
+    // Send and Sync are derived from the wrapped lock, as for std::sync::Mutex
+    const _: () = {
+        const fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MutexWrapped<Vec<i32>>>();
+        assert_send_sync::<RwLockWrapped<Vec<i32>>>();
+    };
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn locations_of_creation_and_acquisition() {
+        let (lock, line) = (RwLockWrapped::new(42), line!());
+        assert_eq!(file!(), lock.state.created_at.file());
+        assert_eq!(line, lock.state.created_at.line());
+        // no start frame in unit tests - the locktag is derived from the location
+        assert!(lock.state.stack_created.is_none());
+        assert_eq!(
+            locktag_from_location(lock.state.created_at),
+            lock.state.locktag()
+        );
+
+        let (guard, line) = (lock.write().unwrap(), line!());
+        let holders = lock.state.holders();
+        assert_eq!(1, holders.len());
+        assert_eq!(file!(), holders[0].location.file());
+        assert_eq!(line, holders[0].location.line());
+        drop(guard);
+    }
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn try_write_for_times_out_with_holder() {
+        let lock = RwLockWrapped::new_named("try_for", 42);
+        let (guard, line) = (lock.read().unwrap(), line!());
+
+        assert_eq!(42, *lock.try_read_for(Duration::from_millis(10)).unwrap());
+        let timeout = match lock.try_write_for(Duration::from_millis(10)) {
+            Err(TryLockForError::Timeout(timeout)) => timeout,
+            other => panic!("expected timeout: {:?}", other.map(|_| ())),
+        };
+        assert_eq!(LockAccess::Write, timeout.access);
+        assert_eq!(Some("try_for"), timeout.name);
+        assert!(timeout.waited >= Duration::from_millis(10));
+        assert_eq!(lock.state.created_at, timeout.created_at);
+        assert_eq!(1, timeout.holders.len());
+        assert_eq!(line, timeout.holders[0].location.line());
+        assert!(timeout.to_string().contains("held by read"));
+
+        drop(guard);
+        assert_eq!(42, *lock.try_write_for(Duration::from_millis(10)).unwrap());
+    }
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn try_write_tracks_holder_and_counts_would_block() {
+        let lock = RwLockWrapped::new(42);
+        let (guard, line) = (lock.try_write().unwrap(), line!());
+        assert!(matches!(lock.try_read(), Err(TryLockError::WouldBlock)));
+        assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+
+        let holders = lock.state.holders();
+        assert_eq!(1, holders.len());
+        assert_eq!(LockAccess::Write, holders[0].access);
+        assert_eq!(line, holders[0].location.line());
+        let stats = lock_stats_by_locktag(lock.state.locktag()).unwrap();
+        assert_eq!(2, stats.would_block);
+        drop(guard);
+        assert!(lock.state.holders().is_empty());
+        assert_eq!(42, *lock.try_read().unwrap());
+    }
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn count_failed_resolution_of_holder_stack() {
+        let lock = MutexWrapped::new(0);
+        let _guard = lock.lock().unwrap();
+        let stack = lock.state.holders()[0].stack.clone().unwrap();
+
+        let before = stack_capture_failures();
+        // all frames up to the test harness belong to this crate and are skipped
+        assert_eq!(
+            Err(BacktrackError::NoStartFrame),
+            stack.resolve().map(|_| ())
+        );
+        let after = stack_capture_failures();
+        assert!(after.no_start_frame > before.no_start_frame);
+        assert_eq!(before.no_debug_symbols, after.no_debug_symbols);
+    }
 }
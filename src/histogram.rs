@@ -0,0 +1,231 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// log-bucketed histogram of durations (nanoseconds) in the spirit of HdrHistogram:
+// each power of two is split into SUB_BUCKETS linear sub-buckets, i.e. the relative error
+// of a quantile is below 1/SUB_BUCKETS (12.5%); values below SUB_BUCKETS are exact
+
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+// covers 0..=u64::MAX
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+fn bucket_index(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+    let exponent = 63 - nanos.leading_zeros();
+    let sub_bucket = (nanos >> (exponent - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    (exponent - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + sub_bucket
+}
+
+// highest value which falls into the bucket
+fn bucket_highest(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let exponent = (index / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+    let sub_bucket = (index % SUB_BUCKETS) as u64;
+    let shift = exponent - SUB_BUCKET_BITS;
+    ((SUB_BUCKETS as u64 + sub_bucket) << shift) + ((1u64 << shift) - 1)
+}
+
+// saturates after ~584 years
+pub(crate) fn as_nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// Snapshot of wait or hold times, see [`crate::lock_stats::LockStats`] and [`crate::lock_stats::call_site_stats`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    max_nanos: u64,
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Histogram")
+            .field("count", &self.count)
+            .field("p50", &self.p50())
+            .field("p99", &self.p99())
+            .field("p999", &self.p999())
+            .field("max", &self.max())
+            .finish()
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            counts: vec![0; BUCKETS],
+            count: 0,
+            max_nanos: 0,
+        }
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        let nanos = as_nanos(duration);
+        self.counts[bucket_index(nanos)] += 1;
+        self.count += 1;
+        self.max_nanos = self.max_nanos.max(nanos);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.max_nanos = self.max_nanos.max(other.max_nanos);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max_nanos)
+    }
+
+    /// Value which a fraction of `quantile` (0.0..=1.0) of all recorded values does not exceed;
+    /// rounded up to the bucket resolution (at most the maximum). Zero if empty.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_highest(index).min(self.max_nanos));
+            }
+        }
+        self.max()
+    }
+
+    pub fn p50(&self) -> Duration {
+        self.quantile(0.5)
+    }
+
+    pub fn p99(&self) -> Duration {
+        self.quantile(0.99)
+    }
+
+    pub fn p999(&self) -> Duration {
+        self.quantile(0.999)
+    }
+}
+
+// lock-free recording on the acquisition path
+pub(crate) struct AtomicHistogram {
+    counts: Box<[AtomicU64]>,
+    max_nanos: AtomicU64,
+}
+
+impl Default for AtomicHistogram {
+    fn default() -> AtomicHistogram {
+        AtomicHistogram {
+            counts: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            max_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+impl AtomicHistogram {
+    pub fn record(&self, duration: Duration) {
+        let nanos = as_nanos(duration);
+        self.counts[bucket_index(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Histogram {
+        let counts: Vec<u64> = self
+            .counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        Histogram {
+            count: counts.iter().sum(),
+            counts,
+            max_nanos: self.max_nanos.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_contiguous() {
+        for nanos in [
+            0,
+            1,
+            7,
+            8,
+            9,
+            15,
+            16,
+            17,
+            1000,
+            1_000_000,
+            u64::MAX / 3,
+            u64::MAX,
+        ] {
+            let index = bucket_index(nanos);
+            assert!(index < BUCKETS);
+            assert!(nanos <= bucket_highest(index));
+            if index > 0 {
+                assert!(nanos > bucket_highest(index - 1));
+            }
+        }
+        assert_eq!(BUCKETS - 1, bucket_index(u64::MAX));
+        assert_eq!(u64::MAX, bucket_highest(BUCKETS - 1));
+    }
+
+    #[test]
+    fn quantiles_within_bucket_resolution() {
+        let mut histogram = Histogram::new();
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        let within = |actual: Duration, expected: Duration| {
+            actual >= expected && actual.as_secs_f64() <= expected.as_secs_f64() * 1.125
+        };
+        assert_eq!(1000, histogram.count());
+        assert!(within(histogram.p50(), Duration::from_micros(500)));
+        assert!(within(histogram.p99(), Duration::from_micros(990)));
+        assert_eq!(Duration::from_millis(1), histogram.p999());
+        assert_eq!(Duration::from_millis(1), histogram.quantile(1.0));
+        assert_eq!(Duration::ZERO, Histogram::new().p99());
+    }
+
+    #[test]
+    fn merge_equals_recording_all() {
+        let (mut fast, mut slow, mut all) = (Histogram::new(), Histogram::new(), Histogram::new());
+        for nanos in 0..100 {
+            fast.record(Duration::from_nanos(nanos));
+            slow.record(Duration::from_millis(nanos));
+            all.record(Duration::from_nanos(nanos));
+            all.record(Duration::from_millis(nanos));
+        }
+        fast.merge(&slow);
+        assert_eq!(all, fast);
+        assert_eq!(Duration::from_millis(99), fast.max());
+
+        let atomic = AtomicHistogram::default();
+        for nanos in 0..100 {
+            atomic.record(Duration::from_nanos(nanos));
+            atomic.record(Duration::from_millis(nanos));
+        }
+        assert_eq!(all, atomic.snapshot());
+    }
+}
@@ -0,0 +1,225 @@
+use crate::deadlock_detector::DeadlockEvent;
+use crate::lock_events::{BlockedEvent, HeldTooLongEvent, LockEventHandler};
+use crate::lock_order::{LockOrderStep, LockOrderViolation};
+use crate::lock_registry::LockHolder;
+use crate::stacktrace_util::{Frame, Stracktrace, UnresolvedStracktrace};
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::io::Write;
+use std::sync::Mutex;
+
+// one JSON object per event (feature "json"); durations are in microseconds
+// e.g. {"event":"blocked","locktag":"xFxiD","access":"write","thread":{"name":"main","id":1},"elapsed_us":2153,...}
+
+/// Emits each event as a single-line JSON object - to the log (default) or to a writer.
+///
+/// ```ignore
+/// lock_events::set_global_event_handler(Arc::new(JsonEventHandler::new()));
+/// ```
+pub struct JsonEventHandler {
+    // None: log on level info with target "rust_debugging_locks::json_events"
+    writer: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl JsonEventHandler {
+    pub fn new() -> JsonEventHandler {
+        JsonEventHandler { writer: None }
+    }
+
+    /// Write one line per event to `writer` (e.g. a file) instead of the log.
+    pub fn with_writer(writer: Box<dyn Write + Send>) -> JsonEventHandler {
+        JsonEventHandler {
+            writer: Some(Mutex::new(writer)),
+        }
+    }
+
+    fn emit(&self, value: Value) {
+        match &self.writer {
+            None => info!("{}", value),
+            Some(writer) => {
+                let mut writer = writer.lock().unwrap();
+                if let Err(err) = writeln!(writer, "{}", value) {
+                    warn!("Unable to write lock event: {}", err);
+                }
+            }
+        }
+    }
+}
+
+impl Default for JsonEventHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LockEventHandler for JsonEventHandler {
+    fn handle_blocked(&self, event: &BlockedEvent) {
+        self.emit(blocked_to_json(event));
+    }
+
+    fn handle_held_too_long(&self, event: &HeldTooLongEvent) {
+        self.emit(held_too_long_to_json(event));
+    }
+
+    fn handle_deadlock(&self, event: &DeadlockEvent) {
+        self.emit(deadlock_to_json(event));
+    }
+
+    fn handle_lock_order_violation(&self, event: &LockOrderViolation) {
+        self.emit(lock_order_violation_to_json(event));
+    }
+}
+
+pub fn blocked_to_json(event: &BlockedEvent) -> Value {
+    json!({
+        "event": "blocked",
+        "locktag": event.locktag,
+        "name": event.name,
+        "access": event.access.to_string(),
+        "location": event.location.to_string(),
+        "created_at": event.created_at.to_string(),
+        "thread": event.thread,
+        "elapsed_us": event.elapsed.as_micros() as u64,
+        "attempt": event.attempt,
+        "stack_caller": event.stack_caller.map(frames),
+        "stack_caller_error": event.stack_caller_error.map(|error| error.to_string()),
+        "holders": event.holders.iter().map(holder_to_json).collect::<Vec<_>>(),
+        "stack_created": event.stack_created.map(frames),
+    })
+}
+
+pub fn held_too_long_to_json(event: &HeldTooLongEvent) -> Value {
+    json!({
+        "event": "held_too_long",
+        "locktag": event.locktag,
+        "name": event.name,
+        "access": event.holder.access.to_string(),
+        "location": event.holder.location.to_string(),
+        "created_at": event.created_at.to_string(),
+        "thread": &*event.holder.thread,
+        "held_for_us": event.held_for.as_micros() as u64,
+        "released": event.released,
+        "stack_holder": unresolved_frames(event.holder.stack.as_deref()),
+        "stack_created": event.stack_created.map(frames),
+    })
+}
+
+pub fn deadlock_to_json(event: &DeadlockEvent) -> Value {
+    let participants: Vec<Value> = event
+        .participants
+        .iter()
+        .map(|participant| {
+            json!({
+                "thread": &*participant.thread,
+                "waiting_for": participant.waiting_for,
+                "name": participant.name,
+                "access": participant.access.to_string(),
+                "location_waiting": participant.location_waiting.to_string(),
+                "created_at": participant.created_at.to_string(),
+                "stack_waiting": unresolved_frames(participant.stack_waiting.as_deref()),
+                "stack_holder": unresolved_frames(participant.stack_holder.as_deref()),
+                "stack_created": participant.stack_created.as_deref().map(frames),
+            })
+        })
+        .collect();
+    json!({
+        "event": "deadlock",
+        "participants": participants,
+    })
+}
+
+pub fn lock_order_violation_to_json(event: &LockOrderViolation) -> Value {
+    json!({
+        "event": "lock_order_violation",
+        "attempted": step_to_json(&event.attempted),
+        "established": event.established.iter().map(step_to_json).collect::<Vec<_>>(),
+    })
+}
+
+fn holder_to_json(holder: &LockHolder) -> Value {
+    json!({
+        "access": holder.access.to_string(),
+        "location": holder.location.to_string(),
+        "thread": &*holder.thread,
+        "held_for_us": holder.since.elapsed().as_micros() as u64,
+        "stack": unresolved_frames(holder.stack.as_deref()),
+        "stack_error": holder.stack_error.map(|error| error.to_string()),
+    })
+}
+
+fn step_to_json(step: &LockOrderStep) -> Value {
+    json!({
+        "from_locktag": step.from_locktag,
+        "to_locktag": step.to_locktag,
+        "from_name": step.from_name,
+        "to_name": step.to_name,
+        "thread": &*step.thread,
+        "location_from": step.location_from.to_string(),
+        "location_to": step.location_to.to_string(),
+        "stack_from": unresolved_frames(step.stack_from.as_deref()),
+        "stack_to": unresolved_frames(step.stack_to.as_deref()),
+    })
+}
+
+fn frames(stacktrace: &Stracktrace) -> &[Frame] {
+    &stacktrace.frames
+}
+
+// null if not available or not resolvable
+fn unresolved_frames(stacktrace: Option<&UnresolvedStracktrace>) -> Option<&[Frame]> {
+    stacktrace
+        .and_then(|stacktrace| stacktrace.resolve().ok())
+        .map(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock_events::LockAccess;
+    use crate::stacktrace_util::ThreadInfo;
+    use std::panic::Location;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn blocked_event_as_json() {
+        let thread = ThreadInfo::current();
+        let stack_created = Stracktrace {
+            frames: vec![Frame {
+                method: "app::init".to_string(),
+                symbol: "app::init::h0123456789abcdef".to_string(),
+                filename: "main.rs".to_string(),
+                line_no: 42,
+            }],
+            hash: "xFxiD".to_string(),
+        };
+        let event = BlockedEvent {
+            locktag: "xFxiD",
+            name: Some("account_cache"),
+            access: LockAccess::Write,
+            location: Location::caller(),
+            created_at: Location::caller(),
+            thread: &thread,
+            since: Instant::now(),
+            elapsed: Duration::from_millis(3),
+            attempt: 2,
+            stack_created: Some(&stack_created),
+            holders: &[],
+            stack_caller: None,
+            stack_caller_error: None,
+        };
+
+        let value = blocked_to_json(&event);
+        assert_eq!("blocked", value["event"]);
+        assert_eq!("account_cache", value["name"]);
+        assert_eq!("write", value["access"]);
+        assert_eq!(3000, value["elapsed_us"]);
+        assert_eq!(2, value["attempt"]);
+        assert!(value["stack_caller"].is_null());
+        assert_eq!(42, value["stack_created"][0]["line_no"]);
+        assert!(value["thread"]["id"].is_u64());
+        assert!(value["location"]
+            .as_str()
+            .unwrap()
+            .starts_with("src/json_events.rs:"));
+    }
+}
@@ -1,4 +1,20 @@
+pub mod deadlock_detector;
+pub mod debug_symbols;
 pub mod debugging_locks;
+pub mod histogram;
+#[cfg(feature = "json")]
+pub mod json_events;
+pub mod lock_events;
+pub mod lock_order;
+pub mod lock_registry;
+pub mod lock_stats;
+pub mod lock_timeout;
+#[cfg(feature = "parking_lot")]
+pub mod parking_lot_locks;
+pub mod prometheus;
 // made public for benchmarking
 pub mod stacktrace_util;
-mod thresholds_config;
+pub mod thresholds_config;
+#[cfg(feature = "tokio")]
+pub mod tokio_locks;
+pub mod watchdog;
@@ -0,0 +1,350 @@
+use crate::deadlock_detector::DeadlockEvent;
+use crate::lock_order::LockOrderViolation;
+use crate::lock_registry::LockHolder;
+use crate::stacktrace_util::{BacktrackError, Stracktrace, ThreadInfo, UnresolvedStracktrace};
+use log::{debug, info, log_enabled, warn, Level};
+use std::fmt;
+use std::fmt::Display;
+use std::panic::Location;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Kind of access a waiter was trying to get when it got blocked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockAccess {
+    // RwLock::read
+    Read,
+    // RwLock::write
+    Write,
+    // Mutex::lock
+    Lock,
+    // parking_lot::RwLock::upgradable_read
+    UpgradableRead,
+}
+
+impl LockAccess {
+    // e.g. "WRITER BLOCKED"
+    pub fn waiter_label(&self) -> &'static str {
+        match self {
+            LockAccess::Read => "READER",
+            LockAccess::Write => "WRITER",
+            LockAccess::Lock => "MUTEX",
+            LockAccess::UpgradableRead => "UPGRADABLE READER",
+        }
+    }
+
+    pub fn lock_type(&self) -> &'static str {
+        match self {
+            LockAccess::Read | LockAccess::Write | LockAccess::UpgradableRead => "rwlock",
+            LockAccess::Lock => "mutex",
+        }
+    }
+}
+
+impl Display for LockAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockAccess::Read => write!(f, "read"),
+            LockAccess::Write => write!(f, "write"),
+            LockAccess::Lock => write!(f, "lock"),
+            LockAccess::UpgradableRead => write!(f, "upgradable read"),
+        }
+    }
+}
+
+/// A thread is waiting for a lock which is held by someone else.
+///
+/// Stacktraces are `None` if they could not be determined (e.g. no debug symbols).
+pub struct BlockedEvent<'a> {
+    // e.g. "NFBZP"
+    pub locktag: &'a str,
+    // see e.g. RwLockWrapped::new_named
+    pub name: Option<&'a str>,
+    pub access: LockAccess,
+    // caller of read/write/lock and of new; available without debug symbols
+    pub location: &'static Location<'static>,
+    pub created_at: &'static Location<'static>,
+    pub thread: &'a ThreadInfo,
+    pub since: Instant,
+    pub elapsed: Duration,
+    // number of blocked events reported before for this wait
+    pub attempt: u64,
+    pub stack_created: Option<&'a Stracktrace>,
+    // all readers or the single writer
+    pub holders: &'a [LockHolder],
+    pub stack_caller: Option<&'a Stracktrace>,
+    // why stack_caller is None (capture or symbolization failed)
+    pub stack_caller_error: Option<BacktrackError>,
+}
+
+/// A guard lived longer than the configured threshold (see [`crate::watchdog`]).
+pub struct HeldTooLongEvent<'a> {
+    pub locktag: &'a str,
+    pub name: Option<&'a str>,
+    pub holder: &'a LockHolder,
+    pub held_for: Duration,
+    // false if reported by the watchdog while the lock is still held
+    pub released: bool,
+    pub created_at: &'static Location<'static>,
+    pub stack_created: Option<&'a Stracktrace>,
+}
+
+/// Callback interface for lock diagnostics.
+///
+/// The implementation must deal with debounce, etc.
+pub trait LockEventHandler: Send + Sync {
+    /// Called from the watchdog thread (see [`crate::watchdog`]); for threads and tokio tasks alike.
+    fn handle_blocked(&self, event: &BlockedEvent);
+
+    /// Called on the releasing thread or from the watchdog thread.
+    fn handle_held_too_long(&self, event: &HeldTooLongEvent) {
+        log_held_too_long(event);
+    }
+
+    /// Called from the deadlock detector thread; only the global handler receives deadlocks.
+    fn handle_deadlock(&self, event: &DeadlockEvent) {
+        log_deadlock(event);
+    }
+
+    /// Called on the thread which acquires the locks in inverted order; only the global handler
+    /// receives lock order violations.
+    fn handle_lock_order_violation(&self, event: &LockOrderViolation) {
+        log_lock_order_violation(event);
+    }
+}
+
+/// Default handler: basic information on level info, stacktraces on level debug.
+pub struct LogEventHandler;
+
+impl LockEventHandler for LogEventHandler {
+    fn handle_blocked(&self, event: &BlockedEvent) {
+        let label = lock_label(event.locktag, event.name);
+        let locktag = label.as_str();
+
+        info!(
+            "{} BLOCKED on thread {} for {:?} at {} (locktag {})",
+            event.access.waiter_label(),
+            event.thread,
+            event.elapsed,
+            event.location,
+            locktag
+        );
+
+        match (event.stack_caller, event.stack_caller_error) {
+            (Some(stacktrace), _) => log_frames("blocking call", locktag, stacktrace),
+            (None, Some(error)) => debug!(" |{}>\tblocking call: n/a ({})", locktag, error),
+            (None, None) => {}
+        }
+
+        for holder in event.holders {
+            log_holder(locktag, holder);
+        }
+
+        let msg = format!("{} constructed here", event.access.lock_type());
+        match event.stack_created {
+            None => log_location(&msg, locktag, event.created_at),
+            Some(stacktrace) => log_frames(&msg, locktag, stacktrace),
+        }
+    }
+}
+
+fn log_held_too_long(event: &HeldTooLongEvent) {
+    let label = lock_label(event.locktag, event.name);
+    let locktag = label.as_str();
+    warn!(
+        "HELD TOO LONG ({}) on thread {} for {:?} at {} (locktag {}, {})",
+        event.holder.access,
+        event.holder.thread,
+        event.held_for,
+        event.holder.location,
+        locktag,
+        if event.released {
+            "released"
+        } else {
+            "still held"
+        }
+    );
+    if let Some(stacktrace) = &event.holder.stack {
+        log_unresolved_frames("acquired here", locktag, stacktrace);
+    }
+    match event.stack_created {
+        None => log_location("lock constructed here", locktag, event.created_at),
+        Some(stacktrace) => log_frames("lock constructed here", locktag, stacktrace),
+    }
+}
+
+fn log_holder(locktag: &str, holder: &LockHolder) {
+    let msg = format!(
+        "current lock holder ({} on thread {} for {:?} at {})",
+        holder.access,
+        holder.thread,
+        holder.since.elapsed(),
+        holder.location
+    );
+    match (&holder.stack, holder.stack_error) {
+        (Some(stacktrace), _) => log_unresolved_frames(&msg, locktag, stacktrace),
+        (None, Some(error)) => debug!(" |{}>\t{}: n/a ({})", locktag, msg, error),
+        (None, None) => debug!(" |{}>\t{}", locktag, msg),
+    }
+}
+
+fn log_deadlock(event: &DeadlockEvent) {
+    warn!("{}", event);
+    for participant in &event.participants {
+        let label = lock_label(&participant.waiting_for, participant.name);
+        let locktag = label.as_str();
+        info!(
+            "thread {} waits for {} at {} (locktag {})",
+            participant.thread, participant.access, participant.location_waiting, locktag
+        );
+        if let Some(stacktrace) = &participant.stack_waiting {
+            log_unresolved_frames("blocking call", locktag, stacktrace);
+        }
+        if let Some(stacktrace) = &participant.stack_holder {
+            log_unresolved_frames("current lock holder", locktag, stacktrace);
+        }
+        match &participant.stack_created {
+            None => log_location("lock constructed here", locktag, participant.created_at),
+            Some(stacktrace) => log_frames("lock constructed here", locktag, stacktrace),
+        }
+    }
+}
+
+fn log_lock_order_violation(event: &LockOrderViolation) {
+    warn!("{}", event);
+    let attempted = &event.attempted;
+    let label = lock_label(&attempted.to_locktag, attempted.to_name);
+    let locktag = label.as_str();
+    if let Some(stacktrace) = &attempted.stack_from {
+        log_unresolved_frames(
+            &format!("holding {} acquired here", attempted.from_label()),
+            locktag,
+            stacktrace,
+        );
+    }
+    if let Some(stacktrace) = &attempted.stack_to {
+        log_unresolved_frames("acquiring call", locktag, stacktrace);
+    }
+    for step in &event.established {
+        info!(
+            "established order {} -> {} on thread {}",
+            step.from_label(),
+            step.to_label(),
+            step.thread
+        );
+        if let Some(stacktrace) = &step.stack_from {
+            log_unresolved_frames(
+                &format!("{} acquired here", step.from_label()),
+                locktag,
+                stacktrace,
+            );
+        }
+        if let Some(stacktrace) = &step.stack_to {
+            log_unresolved_frames(
+                &format!("{} acquired here", step.to_label()),
+                locktag,
+                stacktrace,
+            );
+        }
+    }
+}
+
+/// Locktag and name as shown in log lines, e.g. "xFxiD:account_cache" (or "xFxiD" if unnamed).
+pub fn lock_label(locktag: &str, name: Option<&str>) -> String {
+    match name {
+        None => locktag.to_string(),
+        Some(name) => format!("{}:{}", locktag, name),
+    }
+}
+
+// symbolizes the stacktrace if debug logging is enabled
+fn log_unresolved_frames(msg: &str, locktag: &str, stacktrace: &UnresolvedStracktrace) {
+    if !log_enabled!(Level::Debug) {
+        return;
+    }
+    match stacktrace.resolve() {
+        Ok(stacktrace) => log_frames(msg, locktag, stacktrace),
+        Err(backtrack_error) => debug!(" |{}>\t{}: n/a ({})", locktag, msg, backtrack_error),
+    }
+}
+
+// fallback if the stacktrace is not available
+fn log_location(msg: &str, locktag: &str, location: &Location) {
+    debug!(" |{}>\t{}:", locktag, msg);
+    debug!(" |{}>\t  {}", locktag, location);
+}
+
+fn log_frames(msg: &str, locktag: &str, stacktrace: &Stracktrace) {
+    debug!(" |{}>\t{}:", locktag, msg);
+    for frame in &stacktrace.frames {
+        debug!(
+            " |{}>\t  {}!{}:{}",
+            locktag, frame.filename, frame.method, frame.line_no
+        );
+    }
+}
+
+static GLOBAL_EVENT_HANDLER: RwLock<Option<Arc<dyn LockEventHandler>>> = RwLock::new(None);
+
+// tests which set the global event handler run one after the other
+#[cfg(test)]
+pub(crate) static GLOBAL_HANDLER_TESTS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Replace the default [`LogEventHandler`] for all locks which do not have their own handler.
+pub fn set_global_event_handler(handler: Arc<dyn LockEventHandler>) {
+    *GLOBAL_EVENT_HANDLER.write().unwrap() = Some(handler);
+}
+
+/// Restore the default [`LogEventHandler`].
+pub fn reset_global_event_handler() {
+    *GLOBAL_EVENT_HANDLER.write().unwrap() = None;
+}
+
+// precedence: per-lock handler, global handler, LogEventHandler
+pub(crate) fn dispatch_blocked_event(
+    lock_handler: Option<&Arc<dyn LockEventHandler>>,
+    event: &BlockedEvent,
+) {
+    if let Some(handler) = lock_handler {
+        handler.handle_blocked(event);
+        return;
+    }
+
+    let global_handler = GLOBAL_EVENT_HANDLER.read().unwrap().clone();
+    match global_handler {
+        Some(handler) => handler.handle_blocked(event),
+        None => LogEventHandler.handle_blocked(event),
+    }
+}
+
+pub(crate) fn dispatch_held_too_long_event(
+    lock_handler: Option<&Arc<dyn LockEventHandler>>,
+    event: &HeldTooLongEvent,
+) {
+    if let Some(handler) = lock_handler {
+        handler.handle_held_too_long(event);
+        return;
+    }
+
+    let global_handler = GLOBAL_EVENT_HANDLER.read().unwrap().clone();
+    match global_handler {
+        Some(handler) => handler.handle_held_too_long(event),
+        None => LogEventHandler.handle_held_too_long(event),
+    }
+}
+
+pub(crate) fn dispatch_deadlock_event(event: &DeadlockEvent) {
+    let global_handler = GLOBAL_EVENT_HANDLER.read().unwrap().clone();
+    match global_handler {
+        Some(handler) => handler.handle_deadlock(event),
+        None => LogEventHandler.handle_deadlock(event),
+    }
+}
+
+pub(crate) fn dispatch_lock_order_event(event: &LockOrderViolation) {
+    let global_handler = GLOBAL_EVENT_HANDLER.read().unwrap().clone();
+    match global_handler {
+        Some(handler) => handler.handle_lock_order_violation(event),
+        None => LogEventHandler.handle_lock_order_violation(event),
+    }
+}
@@ -0,0 +1,362 @@
+use crate::debug_symbols::capture_caller_stack;
+use crate::lock_events::{dispatch_lock_order_event, lock_label};
+use crate::lock_registry::LockState;
+use crate::stacktrace_util::{ThreadInfo, UnresolvedStracktrace};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fmt::Display;
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+// lockdep-style validation:
+// learn "lock A was held while lock B got acquired" (A -> B) per locktag and
+// report if some thread acquires in the opposite order - even if that never deadlocks
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static ORDER_GRAPH: Mutex<Option<OrderGraph>> = Mutex::new(None);
+
+thread_local! {
+    // locks currently held by this thread in acquisition order
+    static HELD_LOCKS: RefCell<Vec<HeldLock>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Clone)]
+struct HeldLock {
+    holder_id: u64,
+    locktag: String,
+    name: Option<&'static str>,
+    location: &'static Location<'static>,
+    stack: Option<Arc<UnresolvedStracktrace>>,
+}
+
+/// First observation of "`from` was held while `to` got acquired".
+#[derive(Clone)]
+pub struct LockOrderStep {
+    pub from_locktag: String,
+    pub to_locktag: String,
+    pub from_name: Option<&'static str>,
+    pub to_name: Option<&'static str>,
+    pub thread: Arc<ThreadInfo>,
+    // caller of read/write/lock of `from` and `to`
+    pub location_from: &'static Location<'static>,
+    pub location_to: &'static Location<'static>,
+    // where `from` was acquired
+    pub stack_from: Option<Arc<UnresolvedStracktrace>>,
+    // where `to` was acquired
+    pub stack_to: Option<Arc<UnresolvedStracktrace>>,
+}
+
+impl LockOrderStep {
+    // e.g. "xFxiD:account_cache"
+    pub fn from_label(&self) -> String {
+        lock_label(&self.from_locktag, self.from_name)
+    }
+
+    pub fn to_label(&self) -> String {
+        lock_label(&self.to_locktag, self.to_name)
+    }
+}
+
+/// Lock acquisition which contradicts a previously learned lock order.
+pub struct LockOrderViolation {
+    // the attempted acquisition (`from` is held, `to` gets acquired)
+    pub attempted: LockOrderStep,
+    // previously learned order from `to` back to `from`
+    pub established: Vec<LockOrderStep>,
+}
+
+impl Display for LockOrderViolation {
+    // e.g. "LOCK ORDER INVERSION on thread main:ThreadId(1): acquiring JuCPL while holding xFxiD; established order: JuCPL -> xFxiD"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "LOCK ORDER INVERSION on thread {}: acquiring {} while holding {}; established order: {}",
+            self.attempted.thread,
+            self.attempted.to_label(),
+            self.attempted.from_label(),
+            self.attempted.to_label()
+        )?;
+        for step in &self.established {
+            write!(f, " -> {}", step.to_label())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct OrderGraph {
+    edges: HashMap<String, HashMap<String, LockOrderStep>>,
+    // (held, acquired) pairs which got reported already
+    reported: HashSet<(String, String)>,
+}
+
+impl OrderGraph {
+    fn contains_edge(&self, from: &str, to: &str) -> bool {
+        self.edges
+            .get(from)
+            .map(|targets| targets.contains_key(to))
+            .unwrap_or(false)
+    }
+
+    fn add_edge(&mut self, step: LockOrderStep) {
+        self.edges
+            .entry(step.from_locktag.clone())
+            .or_default()
+            .insert(step.to_locktag.clone(), step);
+    }
+
+    // breadth-first; returns the steps of a shortest path
+    fn find_path(&self, from: &str, to: &str) -> Option<Vec<LockOrderStep>> {
+        let mut predecessor: HashMap<&str, &LockOrderStep> = HashMap::new();
+        let mut queue: VecDeque<&str> = VecDeque::from([from]);
+        let mut seen: HashSet<&str> = HashSet::from([from]);
+
+        while let Some(current) = queue.pop_front() {
+            if current == to {
+                let mut path = vec![];
+                let mut node = to;
+                while node != from {
+                    let step = predecessor[node];
+                    path.push(step.clone());
+                    node = step.from_locktag.as_str();
+                }
+                path.reverse();
+                return Some(path);
+            }
+            if let Some(targets) = self.edges.get(current) {
+                for (next, step) in targets {
+                    if seen.insert(next.as_str()) {
+                        predecessor.insert(next.as_str(), step);
+                        queue.push_back(next.as_str());
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Start learning the lock order; inversions get reported to the global event handler.
+pub fn enable_lock_order_validation() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn disable_lock_order_validation() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+// called before trying to acquire the lock - a real inversion might block forever
+pub(crate) fn before_acquire(state: &LockState, location: &'static Location<'static>) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let locktag = state.locktag();
+
+    // note: stacktraces must not be captured inside of std closures (e.g. LocalKey::with) - resolving stops at /rustc frames
+    let held_locks: Vec<HeldLock> = HELD_LOCKS.with(|held_locks| held_locks.borrow().clone());
+    if held_locks.is_empty() {
+        return;
+    }
+
+    let mut violations = vec![];
+    {
+        let mut order_graph = ORDER_GRAPH.lock().unwrap();
+        let order_graph = order_graph.get_or_insert_with(OrderGraph::default);
+
+        // captured lazily - only required for new edges
+        let mut stack_to: Option<Arc<UnresolvedStracktrace>> = None;
+        let mut stack_to_captured = false;
+        for held in held_locks {
+            let held_locktag = held.locktag;
+            if held_locktag == locktag || order_graph.contains_edge(&held_locktag, locktag) {
+                continue;
+            }
+            if !stack_to_captured {
+                stack_to = capture_caller_stack().ok();
+                stack_to_captured = true;
+            }
+            let step = LockOrderStep {
+                from_locktag: held_locktag.clone(),
+                to_locktag: locktag.to_string(),
+                from_name: held.name,
+                to_name: state.name,
+                thread: Arc::new(ThreadInfo::current()),
+                location_from: held.location,
+                location_to: location,
+                stack_from: held.stack,
+                stack_to: stack_to.clone(),
+            };
+
+            if let Some(established) = order_graph.find_path(locktag, &held_locktag) {
+                if order_graph
+                    .reported
+                    .insert((held_locktag, locktag.to_string()))
+                {
+                    violations.push(LockOrderViolation {
+                        attempted: step,
+                        established,
+                    });
+                }
+                // do not learn the inverted order
+                continue;
+            }
+            order_graph.add_edge(step);
+        }
+    }
+
+    // note: handler might acquire wrapped locks
+    for violation in violations {
+        dispatch_lock_order_event(&violation);
+    }
+}
+
+pub(crate) fn on_acquired(
+    state: &LockState,
+    holder_id: u64,
+    location: &'static Location<'static>,
+    stack: Option<Arc<UnresolvedStracktrace>>,
+) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    HELD_LOCKS.with(|held_locks| {
+        held_locks.borrow_mut().push(HeldLock {
+            holder_id,
+            locktag: state.locktag().to_string(),
+            name: state.name,
+            location,
+            stack,
+        });
+    });
+}
+
+pub(crate) fn on_released(holder_id: u64) {
+    // note: must not check ENABLED - validation might have been disabled while holding the lock
+    let _ = HELD_LOCKS.try_with(|held_locks| {
+        held_locks
+            .borrow_mut()
+            .retain(|held| held.holder_id != holder_id);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugging_locks::MutexWrapped;
+    use crate::lock_events::{
+        reset_global_event_handler, set_global_event_handler, BlockedEvent, LockEventHandler,
+        GLOBAL_HANDLER_TESTS,
+    };
+    use std::sync::{MutexGuard, PoisonError};
+
+    fn step(from: &str, to: &str) -> LockOrderStep {
+        LockOrderStep {
+            from_locktag: from.to_string(),
+            to_locktag: to.to_string(),
+            from_name: None,
+            to_name: None,
+            thread: Arc::new(ThreadInfo::current()),
+            location_from: Location::caller(),
+            location_to: Location::caller(),
+            stack_from: None,
+            stack_to: None,
+        }
+    }
+
+    #[test]
+    fn find_transitive_path() {
+        let mut graph = OrderGraph::default();
+        graph.add_edge(step("A", "B"));
+        graph.add_edge(step("B", "C"));
+
+        let path = graph.find_path("A", "C").unwrap();
+        let locktags: Vec<&str> = path.iter().map(|s| s.to_locktag.as_str()).collect();
+        assert_eq!(vec!["B", "C"], locktags);
+
+        // C -> A would be an inversion; A -> C is fine
+        assert!(graph.find_path("C", "A").is_none());
+    }
+
+    // (held, acquired) names of all violations
+    #[derive(Default)]
+    struct ViolationRecorder(Mutex<Vec<(Option<&'static str>, Option<&'static str>)>>);
+
+    impl LockEventHandler for ViolationRecorder {
+        fn handle_blocked(&self, _event: &BlockedEvent) {}
+
+        fn handle_lock_order_violation(&self, event: &LockOrderViolation) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((event.attempted.from_name, event.attempted.to_name));
+        }
+    }
+
+    // acquires a then b, and b then a (twice); returns the violations involving a or b
+    fn acquire_both_orders(
+        a: &MutexWrapped<i32>,
+        b: &MutexWrapped<i32>,
+        enabled: bool,
+    ) -> Vec<(Option<&'static str>, Option<&'static str>)> {
+        // the global handler and the validation switch are shared by all tests
+        let _global: MutexGuard<()> = GLOBAL_HANDLER_TESTS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let recorder = Arc::new(ViolationRecorder::default());
+        set_global_event_handler(recorder.clone());
+        if enabled {
+            enable_lock_order_validation();
+        }
+
+        for _ in 0..2 {
+            let guard_a = a.lock().unwrap();
+            drop(b.lock().unwrap());
+            drop(guard_a);
+            let guard_b = b.lock().unwrap();
+            drop(a.lock().unwrap());
+            drop(guard_b);
+        }
+
+        disable_lock_order_validation();
+        reset_global_event_handler();
+        let names = [a.name(), b.name()];
+        let violations = recorder.0.lock().unwrap();
+        violations
+            .iter()
+            .filter(|(from, to)| names.contains(from) || names.contains(to))
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn report_inverted_order_once() {
+        let a = MutexWrapped::new_named("inverted_a", 0);
+        let b = MutexWrapped::new_named("inverted_b", 0);
+
+        let violations = acquire_both_orders(&a, &b, true);
+
+        assert_eq!(vec![(Some("inverted_b"), Some("inverted_a"))], violations);
+    }
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn ignore_locks_of_the_same_class() {
+        let locks: Vec<MutexWrapped<i32>> = (0..2)
+            .map(|_| MutexWrapped::new_named("same_class", 0))
+            .collect();
+
+        assert!(acquire_both_orders(&locks[0], &locks[1], true).is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn ignore_acquisitions_while_disabled() {
+        let a = MutexWrapped::new_named("disabled_a", 0);
+        let b = MutexWrapped::new_named("disabled_b", 0);
+
+        assert!(acquire_both_orders(&a, &b, false).is_empty());
+    }
+}
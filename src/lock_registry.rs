@@ -0,0 +1,380 @@
+use crate::debugging_locks::diagnostics_enabled;
+use crate::lock_events::{LockAccess, LockEventHandler};
+use crate::lock_order;
+use crate::lock_stats::{counters_for, lock_stats, LockCounters};
+use crate::stacktrace_util::{
+    locktag_from_location, BacktrackError, Stracktrace, ThreadInfo, UnresolvedStracktrace,
+};
+use crate::thresholds_config::ThresholdsConfig;
+use crate::watchdog;
+use std::collections::HashMap;
+use std::panic::Location;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+// global registry of all wrapped locks; used by the background checks (e.g. deadlock detector)
+
+pub(crate) type LockId = u64;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+// entries are removed when the lock gets dropped (see Drop for LockState)
+static REGISTRY: Mutex<Option<HashMap<LockId, Weak<LockState>>>> = Mutex::new(None);
+// number of waiters of all locks; lets the watchdog skip the scan
+static WAITER_COUNT: AtomicUsize = AtomicUsize::new(0);
+// holders are spread over shards by holder id: concurrent readers rarely lock the same shard
+const HOLDER_SHARDS: usize = 8;
+
+fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Diagnostic state shared by all wrapper types.
+pub(crate) struct LockState {
+    pub id: LockId,
+    pub stack_created: Option<Arc<Stracktrace>>,
+    // where the lock got created; always available (unlike stack_created)
+    pub created_at: &'static Location<'static>,
+    // hash of stack_created or - as fallback - of created_at
+    locktag: String,
+    // human label, e.g. "account_cache"; shown next to the locktag
+    pub name: Option<&'static str>,
+    // overrides the global event handler
+    event_handler: Mutex<Option<Arc<dyn LockEventHandler>>>,
+    holders: [Mutex<Vec<LockHolder>>; HOLDER_SHARDS],
+    waiters: Mutex<Vec<LockWaiter>>,
+    // shared with all locks of the same locktag and name; None (and not registered) if the
+    // lock got created while diagnostics were disabled
+    stats: Option<Arc<LockCounters>>,
+}
+
+/// Thread which currently holds a lock; i.e. the guard was not dropped yet.
+#[derive(Clone)]
+pub struct LockHolder {
+    pub(crate) holder_id: u64,
+    pub thread: Arc<ThreadInfo>,
+    pub access: LockAccess,
+    pub since: Instant,
+    // caller of read/write/lock; always available (unlike stack)
+    pub location: &'static Location<'static>,
+    // where the lock got acquired
+    pub stack: Option<Arc<UnresolvedStracktrace>>,
+    // why stack is None
+    pub stack_error: Option<BacktrackError>,
+}
+
+#[derive(Clone)]
+pub(crate) struct LockWaiter {
+    pub waiter_id: u64,
+    pub thread: Arc<ThreadInfo>,
+    pub access: LockAccess,
+    pub since: Instant,
+    pub location: &'static Location<'static>,
+    pub stack: Option<Arc<UnresolvedStracktrace>>,
+    pub stack_error: Option<BacktrackError>,
+    // elapsed wait time of the previous blocked event
+    pub last_report: Option<Duration>,
+    // number of blocked events reported so far
+    pub reports: u64,
+    // async waiter: the task does not block the thread (see deadlock detector)
+    pub task: bool,
+}
+
+impl LockState {
+    pub fn new(
+        stack_created: Option<Stracktrace>,
+        name: Option<&'static str>,
+        created_at: &'static Location<'static>,
+    ) -> Arc<LockState> {
+        let locktag = match &stack_created {
+            Some(stacktrace) => stacktrace.hash.clone(),
+            None => locktag_from_location(created_at),
+        };
+        let registered = diagnostics_enabled();
+        let stats = registered.then(|| counters_for(&locktag, name));
+        let state = Arc::new(LockState {
+            id: next_id(),
+            stack_created: stack_created.map(Arc::new),
+            created_at,
+            locktag,
+            name,
+            event_handler: Mutex::new(None),
+            holders: std::array::from_fn(|_| Mutex::new(Vec::new())),
+            waiters: Mutex::new(Vec::new()),
+            stats,
+        });
+
+        if registered {
+            REGISTRY
+                .lock()
+                .unwrap()
+                .get_or_insert_with(HashMap::new)
+                .insert(state.id, Arc::downgrade(&state));
+        }
+
+        state
+    }
+
+    // e.g. "NFBZP"
+    pub fn locktag(&self) -> &str {
+        &self.locktag
+    }
+
+    pub fn stats(&self) -> Option<&LockCounters> {
+        self.stats.as_deref()
+    }
+
+    pub fn set_event_handler(&self, handler: Arc<dyn LockEventHandler>) {
+        *self.event_handler.lock().unwrap() = Some(handler);
+    }
+
+    pub fn event_handler(&self) -> Option<Arc<dyn LockEventHandler>> {
+        self.event_handler.lock().unwrap().clone()
+    }
+
+    pub fn register_holder(
+        &self,
+        access: LockAccess,
+        location: &'static Location<'static>,
+        stack: Result<Arc<UnresolvedStracktrace>, BacktrackError>,
+    ) -> HolderToken<'_> {
+        let holder_id = next_id();
+        lock_order::on_acquired(self, holder_id, location, stack.clone().ok());
+        self.push_holder(holder_id, access, location, stack)
+    }
+
+    // holder of an async lock: the guard may move between threads, thus it is not
+    // tracked for lock order validation (thread local)
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub fn register_task_holder(
+        &self,
+        access: LockAccess,
+        location: &'static Location<'static>,
+        stack: Result<Arc<UnresolvedStracktrace>, BacktrackError>,
+    ) -> HolderToken<'_> {
+        self.push_holder(next_id(), access, location, stack)
+    }
+
+    fn push_holder(
+        &self,
+        holder_id: u64,
+        access: LockAccess,
+        location: &'static Location<'static>,
+        stack: Result<Arc<UnresolvedStracktrace>, BacktrackError>,
+    ) -> HolderToken<'_> {
+        if let Some(stats) = self.stats() {
+            stats.record_acquired(access);
+        }
+        self.holder_shard(holder_id)
+            .lock()
+            .unwrap()
+            .push(LockHolder {
+                holder_id,
+                thread: Arc::new(ThreadInfo::current()),
+                access,
+                since: Instant::now(),
+                location,
+                stack_error: stack.as_ref().err().copied(),
+                stack: stack.ok(),
+            });
+        HolderToken {
+            state: self,
+            holder_id,
+        }
+    }
+
+    pub fn register_waiter(
+        &self,
+        access: LockAccess,
+        location: &'static Location<'static>,
+        stack: Result<Arc<UnresolvedStracktrace>, BacktrackError>,
+    ) -> WaiterToken<'_> {
+        self.push_waiter(access, location, stack, false)
+    }
+
+    // waiter of an async lock: reported by the watchdog like a blocked thread, but not part of
+    // the wait-for graph of the deadlock detector
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub fn register_task_waiter(
+        &self,
+        access: LockAccess,
+        location: &'static Location<'static>,
+        stack: Result<Arc<UnresolvedStracktrace>, BacktrackError>,
+    ) -> WaiterToken<'_> {
+        self.push_waiter(access, location, stack, true)
+    }
+
+    fn push_waiter(
+        &self,
+        access: LockAccess,
+        location: &'static Location<'static>,
+        stack: Result<Arc<UnresolvedStracktrace>, BacktrackError>,
+        task: bool,
+    ) -> WaiterToken<'_> {
+        let waiter_id = next_id();
+        self.waiters.lock().unwrap().push(LockWaiter {
+            waiter_id,
+            thread: Arc::new(ThreadInfo::current()),
+            access,
+            since: Instant::now(),
+            location,
+            stack_error: stack.as_ref().err().copied(),
+            stack: stack.ok(),
+            last_report: None,
+            reports: 0,
+            task,
+        });
+        // note: SeqCst pairs with watchdog::park
+        WAITER_COUNT.fetch_add(1, Ordering::SeqCst);
+        watchdog::unpark();
+        WaiterToken {
+            state: self,
+            waiter_id,
+        }
+    }
+
+    fn holder_shard(&self, holder_id: u64) -> &Mutex<Vec<LockHolder>> {
+        &self.holders[holder_id as usize % HOLDER_SHARDS]
+    }
+
+    // in acquisition order
+    pub fn holders(&self) -> Vec<LockHolder> {
+        let mut holders: Vec<LockHolder> = self
+            .holders
+            .iter()
+            .flat_map(|shard| shard.lock().unwrap().clone())
+            .collect();
+        holders.sort_by_key(|holder| holder.since);
+        holders
+    }
+
+    pub fn waiters(&self) -> Vec<LockWaiter> {
+        self.waiters.lock().unwrap().clone()
+    }
+
+    // waiters which are due for a blocked event (with the elapsed wait time); marks them as reported
+    pub fn due_waiters(&self, config: &ThresholdsConfig) -> Vec<(LockWaiter, Duration)> {
+        let mut due = vec![];
+        for waiter in self.waiters.lock().unwrap().iter_mut() {
+            let elapsed = waiter.since.elapsed();
+            if config.should_report(elapsed, waiter.last_report) {
+                due.push((waiter.clone(), elapsed));
+                waiter.last_report = Some(elapsed);
+                waiter.reports += 1;
+            }
+        }
+        due
+    }
+}
+
+impl Drop for LockState {
+    fn drop(&mut self) {
+        // created while diagnostics were disabled: never registered
+        if self.stats.is_none() {
+            return;
+        }
+        if let Some(registry) = REGISTRY.lock().unwrap().as_mut() {
+            registry.remove(&self.id);
+        }
+    }
+}
+
+/// Tracks the holder until the guard gets dropped; see [`HolderToken::release`].
+pub(crate) struct HolderToken<'a> {
+    state: &'a LockState,
+    holder_id: u64,
+}
+
+impl<'a> HolderToken<'a> {
+    pub fn state(&self) -> &'a LockState {
+        self.state
+    }
+
+    // deregister the holder, release the lock using `unlock` and check the hold time
+    // note: deregister before unlocking - otherwise the next holder might see a stale holder
+    pub fn release<F: FnOnce()>(&mut self, unlock: F) {
+        lock_order::on_released(self.holder_id);
+        let holder = {
+            let mut holders = self.state.holder_shard(self.holder_id).lock().unwrap();
+            holders
+                .iter()
+                .position(|holder| holder.holder_id == self.holder_id)
+                .map(|pos| holders.remove(pos))
+        };
+
+        unlock();
+
+        if let Some(holder) = holder {
+            if let Some(stats) = self.state.stats() {
+                stats.record_hold(holder.since.elapsed(), holder.location);
+            }
+            watchdog::on_released(self.state, &holder);
+        }
+    }
+}
+
+/// Deregisters the waiter when the lock got acquired (or acquisition failed).
+pub(crate) struct WaiterToken<'a> {
+    state: &'a LockState,
+    waiter_id: u64,
+}
+
+impl Drop for WaiterToken<'_> {
+    fn drop(&mut self) {
+        let mut waiters = self.state.waiters.lock().unwrap();
+        if let Some(pos) = waiters
+            .iter()
+            .position(|waiter| waiter.waiter_id == self.waiter_id)
+        {
+            let waiter = waiters.remove(pos);
+            if let Some(stats) = self.state.stats() {
+                stats.record_wait(waiter.since.elapsed(), waiter.location);
+            }
+        }
+        WAITER_COUNT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn has_waiters() -> bool {
+    WAITER_COUNT.load(Ordering::SeqCst) > 0
+}
+
+pub(crate) fn all_locks() -> Vec<Arc<LockState>> {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|registry| registry.values())
+        .filter_map(|weak| weak.upgrade())
+        .collect()
+}
+
+/// Number of failed non-blocking acquisitions (`try_read`, `try_write`, `try_lock`) by locktag.
+///
+/// Summed over all locks (including dropped ones) sharing a locktag; locktags without failures
+/// are omitted. See [`crate::lock_stats::lock_stats`] for all counters.
+pub fn would_block_counts() -> HashMap<String, u64> {
+    let mut counts = HashMap::new();
+    for stats in lock_stats() {
+        if stats.would_block > 0 {
+            *counts.entry(stats.locktag).or_insert(0) += stats.would_block;
+        }
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn dropped_lock_leaves_registry() {
+        let state = LockState::new(None, None, Location::caller());
+        let id = state.id;
+        assert!(all_locks().iter().any(|lock| lock.id == id));
+
+        drop(state);
+        assert!(!all_locks().iter().any(|lock| lock.id == id));
+    }
+}
@@ -0,0 +1,381 @@
+use crate::histogram::{as_nanos, AtomicHistogram, Histogram};
+use crate::lock_events::LockAccess;
+use crate::stacktrace_util::locktag_from_location;
+use std::collections::HashMap;
+use std::panic::Location;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
+
+// counters of all wrapped locks keyed by locktag and name; locks with the same creation site
+// (and name) share their counters, which survive the locks - short-lived locks add up
+
+type CountersKey = (String, Option<&'static str>);
+
+static COUNTERS: Mutex<Option<HashMap<CountersKey, Arc<LockCounters>>>> = Mutex::new(None);
+
+// bounds memory: each call site holds up to two histograms of ~4 KB (allocated on first use),
+// i.e. at most ~512 KB per lock class; acquisitions from further call sites are counted in the
+// histograms of the lock only
+const CALL_SITES_LIMIT: usize = 64;
+
+// call sites are keyed by the caller of read/write/lock (#[track_caller]), i.e. the first frame
+// outside of this crate - the number of call sites is bounded by the code, even for recursion
+struct CallSiteCounters {
+    wait: OnceLock<AtomicHistogram>,
+    hold: OnceLock<AtomicHistogram>,
+}
+
+#[derive(Default)]
+pub(crate) struct LockCounters {
+    read_acquisitions: AtomicU64,
+    write_acquisitions: AtomicU64,
+    contended: AtomicU64,
+    wait_total_nanos: AtomicU64,
+    wait_max_nanos: AtomicU64,
+    hold_total_nanos: AtomicU64,
+    hold_max_nanos: AtomicU64,
+    poisonings: AtomicU64,
+    would_block: AtomicU64,
+    wait_histogram: AtomicHistogram,
+    hold_histogram: AtomicHistogram,
+    // write-locked only to add a call site
+    call_sites: RwLock<HashMap<&'static Location<'static>, Arc<CallSiteCounters>>>,
+}
+
+impl LockCounters {
+    pub fn record_acquired(&self, access: LockAccess) {
+        let counter = match access {
+            LockAccess::Read | LockAccess::UpgradableRead => &self.read_acquisitions,
+            LockAccess::Write | LockAccess::Lock => &self.write_acquisitions,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // also called for waits which timed out
+    pub fn record_wait(&self, waited: Duration, location: &'static Location<'static>) {
+        let nanos = as_nanos(waited);
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.wait_total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.wait_max_nanos.fetch_max(nanos, Ordering::Relaxed);
+        self.wait_histogram.record(waited);
+        if let Some(call_site) = self.call_site(location) {
+            call_site
+                .wait
+                .get_or_init(AtomicHistogram::default)
+                .record(waited);
+        }
+    }
+
+    pub fn record_hold(&self, held: Duration, location: &'static Location<'static>) {
+        let nanos = as_nanos(held);
+        self.hold_total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.hold_max_nanos.fetch_max(nanos, Ordering::Relaxed);
+        self.hold_histogram.record(held);
+        if let Some(call_site) = self.call_site(location) {
+            call_site
+                .hold
+                .get_or_init(AtomicHistogram::default)
+                .record(held);
+        }
+    }
+
+    // None if the call site is new and the limit is reached
+    fn call_site(&self, location: &'static Location<'static>) -> Option<Arc<CallSiteCounters>> {
+        if let Some(call_site) = self.call_sites.read().unwrap().get(location) {
+            return Some(call_site.clone());
+        }
+        let mut call_sites = self.call_sites.write().unwrap();
+        if call_sites.len() >= CALL_SITES_LIMIT && !call_sites.contains_key(location) {
+            return None;
+        }
+        let call_site = call_sites.entry(location).or_insert_with(|| {
+            Arc::new(CallSiteCounters {
+                wait: OnceLock::new(),
+                hold: OnceLock::new(),
+            })
+        });
+        Some(call_site.clone())
+    }
+
+    pub fn record_poisoned(&self) {
+        self.poisonings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_would_block(&self) {
+        self.would_block.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, (locktag, name): &CountersKey) -> LockStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        LockStats {
+            locktag: locktag.clone(),
+            name: *name,
+            read_acquisitions: load(&self.read_acquisitions),
+            write_acquisitions: load(&self.write_acquisitions),
+            contended: load(&self.contended),
+            wait_total: Duration::from_nanos(load(&self.wait_total_nanos)),
+            wait_max: Duration::from_nanos(load(&self.wait_max_nanos)),
+            hold_total: Duration::from_nanos(load(&self.hold_total_nanos)),
+            hold_max: Duration::from_nanos(load(&self.hold_max_nanos)),
+            poisonings: load(&self.poisonings),
+            would_block: load(&self.would_block),
+            wait_histogram: self.wait_histogram.snapshot(),
+            hold_histogram: self.hold_histogram.snapshot(),
+        }
+    }
+}
+
+pub(crate) fn counters_for(locktag: &str, name: Option<&'static str>) -> Arc<LockCounters> {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .entry((locktag.to_string(), name))
+        .or_default()
+        .clone()
+}
+
+/// Snapshot of the counters of all locks with the same locktag and name.
+///
+/// Acquisitions of the mutexes count as write; the counters are not updated while the
+/// diagnostics are disabled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockStats {
+    pub locktag: String,
+    pub name: Option<&'static str>,
+    // read and upgradable read
+    pub read_acquisitions: u64,
+    // write and lock
+    pub write_acquisitions: u64,
+    // acquisitions which had to wait (including timed out try_*_for)
+    pub contended: u64,
+    pub wait_total: Duration,
+    pub wait_max: Duration,
+    pub hold_total: Duration,
+    pub hold_max: Duration,
+    // write or mutex guards dropped while panicking, i.e. the lock got poisoned (std locks only)
+    pub poisonings: u64,
+    // failed try_read/try_write/try_lock
+    pub would_block: u64,
+    pub wait_histogram: Histogram,
+    pub hold_histogram: Histogram,
+}
+
+impl LockStats {
+    pub fn acquisitions(&self) -> u64 {
+        self.read_acquisitions + self.write_acquisitions
+    }
+
+    /// Add the counters of `other`; locktag and name are kept.
+    pub fn merge(&mut self, other: &LockStats) {
+        self.read_acquisitions += other.read_acquisitions;
+        self.write_acquisitions += other.write_acquisitions;
+        self.contended += other.contended;
+        self.wait_total += other.wait_total;
+        self.wait_max = self.wait_max.max(other.wait_max);
+        self.hold_total += other.hold_total;
+        self.hold_max = self.hold_max.max(other.hold_max);
+        self.poisonings += other.poisonings;
+        self.would_block += other.would_block;
+        self.wait_histogram.merge(&other.wait_histogram);
+        self.hold_histogram.merge(&other.hold_histogram);
+    }
+}
+
+/// Counters of all locks created so far (including dropped ones), one entry per locktag and name.
+pub fn lock_stats() -> Vec<LockStats> {
+    let mut stats: Vec<LockStats> = COUNTERS
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|counters| counters.iter())
+        .map(|(key, counters)| counters.snapshot(key))
+        .collect();
+    stats.sort_by(|a, b| (&a.locktag, a.name).cmp(&(&b.locktag, b.name)));
+    stats
+}
+
+/// Counters of the locks created at the site identified by `locktag` (merged over all names).
+pub fn lock_stats_by_locktag(locktag: &str) -> Option<LockStats> {
+    merged(
+        lock_stats()
+            .into_iter()
+            .filter(|stats| stats.locktag == locktag),
+    )
+}
+
+/// Counters of the locks named `name` (merged over all creation sites); see e.g.
+/// [`crate::debugging_locks::RwLockWrapped::new_named`].
+pub fn lock_stats_by_name(name: &str) -> Option<LockStats> {
+    merged(
+        lock_stats()
+            .into_iter()
+            .filter(|stats| stats.name == Some(name)),
+    )
+}
+
+/// Wait and hold times of the acquisitions from one call site.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallSiteStats {
+    pub locktag: String,
+    pub name: Option<&'static str>,
+    // locktag of the location, i.e. a hash of the caller's source location
+    pub call_site: String,
+    // caller of read/write/lock
+    pub location: &'static Location<'static>,
+    pub wait_histogram: Histogram,
+    pub hold_histogram: Histogram,
+}
+
+/// Histograms per acquiring call site of the locks with `locktag`, sorted by call site.
+///
+/// A call site is the caller of read/write/lock, i.e. the first frame outside of this crate; call sites of
+/// locks with different names are merged. Memory per lock class is bounded: at most 64 call sites with two
+/// histograms of ~4 KB each (allocated on first use), i.e. ~512 KB in the worst case; acquisitions from
+/// further call sites are only recorded in [`LockStats::wait_histogram`] and [`LockStats::hold_histogram`].
+pub fn call_site_stats(locktag: &str) -> Vec<CallSiteStats> {
+    let mut merged: HashMap<&'static Location<'static>, CallSiteStats> = HashMap::new();
+    let counters = COUNTERS.lock().unwrap();
+    let counters = counters
+        .iter()
+        .flat_map(|counters| counters.iter())
+        .filter(|((tag, _), _)| tag == locktag);
+    for ((_, name), counters) in counters {
+        for (location, call_site) in counters.call_sites.read().unwrap().iter() {
+            let snapshot = |histogram: &OnceLock<AtomicHistogram>| {
+                histogram
+                    .get()
+                    .map(AtomicHistogram::snapshot)
+                    .unwrap_or_default()
+            };
+            let (wait_histogram, hold_histogram) =
+                (snapshot(&call_site.wait), snapshot(&call_site.hold));
+            match merged.get_mut(location) {
+                Some(stats) => {
+                    stats.wait_histogram.merge(&wait_histogram);
+                    stats.hold_histogram.merge(&hold_histogram);
+                }
+                None => {
+                    merged.insert(
+                        location,
+                        CallSiteStats {
+                            locktag: locktag.to_string(),
+                            name: *name,
+                            call_site: locktag_from_location(location),
+                            location,
+                            wait_histogram,
+                            hold_histogram,
+                        },
+                    );
+                }
+            }
+        }
+    }
+    let mut stats: Vec<CallSiteStats> = merged.into_values().collect();
+    stats.sort_by(|a, b| a.call_site.cmp(&b.call_site));
+    stats
+}
+
+fn merged(mut stats: impl Iterator<Item = LockStats>) -> Option<LockStats> {
+    let mut merged = stats.next()?;
+    for other in stats {
+        merged.merge(&other);
+    }
+    Some(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugging_locks::{MutexWrapped, RwLockWrapped};
+    use std::thread;
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn count_acquisitions_waits_and_holds() {
+        let lock = Arc::new(RwLockWrapped::new_named("stats_test", 0));
+        drop(lock.read().unwrap());
+        let guard = lock.write().unwrap();
+        let waiter = {
+            let lock = lock.clone();
+            thread::spawn(move || *lock.read().unwrap())
+        };
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        waiter.join().unwrap();
+        assert!(lock.try_write_for(Duration::ZERO).is_ok());
+
+        let stats = lock_stats_by_name("stats_test").unwrap();
+        assert_eq!(2, stats.read_acquisitions);
+        assert_eq!(2, stats.write_acquisitions);
+        assert_eq!(1, stats.contended);
+        assert!(stats.wait_max > Duration::ZERO);
+        assert!(stats.hold_max >= Duration::from_millis(20));
+        assert!(stats.hold_total >= stats.hold_max);
+        assert_eq!(1, stats.wait_histogram.count());
+        assert_eq!(4, stats.hold_histogram.count());
+        assert!(stats.hold_histogram.p999() >= Duration::from_millis(20));
+        assert_eq!(Some(stats.clone()), lock_stats_by_locktag(&stats.locktag));
+    }
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn histograms_per_call_site() {
+        let lock = RwLockWrapped::new_named("call_site_test", 0);
+        let line_read = line!() + 2;
+        for _ in 0..3 {
+            drop(lock.read().unwrap());
+        }
+        let line_write = line!() + 1;
+        drop(lock.write().unwrap());
+
+        let locktag = lock_stats_by_name("call_site_test").unwrap().locktag;
+        let call_sites = call_site_stats(&locktag);
+        assert_eq!(2, call_sites.len());
+        let hold_count = |line: u32| {
+            call_sites
+                .iter()
+                .find(|stats| stats.location.line() == line)
+                .map(|stats| stats.hold_histogram.count())
+        };
+        assert_eq!(Some(3), hold_count(line_read));
+        assert_eq!(Some(1), hold_count(line_write));
+    }
+
+    #[test]
+    fn merge_adds_counters_and_keeps_maximum() {
+        let mut stats = counters_for("merge_test", None).snapshot(&("merge_test".into(), None));
+        let other = LockStats {
+            read_acquisitions: 3,
+            wait_max: Duration::from_millis(5),
+            wait_total: Duration::from_millis(7),
+            ..stats.clone()
+        };
+        stats.merge(&other);
+        stats.merge(&other);
+        assert_eq!(6, stats.read_acquisitions);
+        assert_eq!(Duration::from_millis(14), stats.wait_total);
+        assert_eq!(Duration::from_millis(5), stats.wait_max);
+    }
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn count_poisoning_once() {
+        let lock = Arc::new(MutexWrapped::new_named("poisoning_test", 0));
+        let panicked = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let _guard = lock.lock().unwrap();
+                panic!("poison the lock");
+            })
+            .join()
+        };
+        assert!(panicked.is_err());
+        // acquiring the poisoned lock does not count
+        assert!(lock.lock().is_err());
+        assert!(lock.lock().is_err());
+
+        assert_eq!(1, lock_stats_by_name("poisoning_test").unwrap().poisonings);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::lock_events::{lock_label, LockAccess};
+use crate::lock_registry::{LockHolder, LockState};
+use crate::stacktrace_util::Stracktrace;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Debug, Display};
+use std::panic::Location;
+use std::sync::{Arc, PoisonError};
+use std::time::Duration;
+
+/// Result of the timed acquisitions, e.g. [`crate::debugging_locks::RwLockWrapped::try_write_for`].
+pub type TryLockForResult<G> = Result<G, TryLockForError<G>>;
+
+/// Like [`std::sync::TryLockError`]; instead of `WouldBlock` the timeout carries what the lock was blocked by.
+pub enum TryLockForError<G> {
+    Poisoned(PoisonError<G>),
+    Timeout(LockTimeout),
+}
+
+/// The lock could not be acquired before the deadline; snapshot of the lock at that time.
+///
+/// `{:#}` additionally prints the stacktraces of the holders and of the creation.
+pub struct LockTimeout {
+    pub locktag: String,
+    pub name: Option<&'static str>,
+    pub access: LockAccess,
+    pub waited: Duration,
+    // caller of try_read_for/try_write_for
+    pub location: &'static Location<'static>,
+    pub created_at: &'static Location<'static>,
+    pub stack_created: Option<Arc<Stracktrace>>,
+    // empty if the diagnostics are disabled
+    pub holders: Vec<LockHolder>,
+}
+
+impl LockTimeout {
+    pub(crate) fn new(
+        state: &LockState,
+        access: LockAccess,
+        waited: Duration,
+        location: &'static Location<'static>,
+    ) -> LockTimeout {
+        LockTimeout {
+            locktag: state.locktag().to_string(),
+            name: state.name,
+            access,
+            waited,
+            location,
+            created_at: state.created_at,
+            stack_created: state.stack_created.clone(),
+            holders: state.holders(),
+        }
+    }
+}
+
+impl Display for LockTimeout {
+    // e.g. "timed out after 10ms waiting for write at src/main.rs:12:5 (locktag xFxiD); held by read on thread main:ThreadId(1) at src/main.rs:8:5"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "timed out after {:?} waiting for {} at {} (locktag {})",
+            self.waited,
+            self.access,
+            self.location,
+            lock_label(&self.locktag, self.name)
+        )?;
+        for (idx, holder) in self.holders.iter().enumerate() {
+            let separator = if idx == 0 { "; held by" } else { "," };
+            write!(
+                f,
+                "{} {} on thread {} at {}",
+                separator, holder.access, holder.thread, holder.location
+            )?;
+        }
+        if !f.alternate() {
+            return Ok(());
+        }
+
+        for holder in &self.holders {
+            writeln!(f)?;
+            write!(f, "  {} acquired here:", holder.access)?;
+            match holder.stack.as_deref().map(|stack| stack.resolve()) {
+                Some(Ok(stacktrace)) => write_frames(f, stacktrace)?,
+                Some(Err(error)) => write!(f, " n/a ({})", error)?,
+                None => write!(f, " n/a")?,
+            }
+        }
+        writeln!(f)?;
+        write!(
+            f,
+            "  {} constructed at {}:",
+            self.access.lock_type(),
+            self.created_at
+        )?;
+        if let Some(stacktrace) = &self.stack_created {
+            write_frames(f, stacktrace)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_frames(f: &mut fmt::Formatter, stacktrace: &Stracktrace) -> fmt::Result {
+    for frame in &stacktrace.frames {
+        write!(
+            f,
+            "\n    {}!{}:{}",
+            frame.filename, frame.method, frame.line_no
+        )?;
+    }
+    Ok(())
+}
+
+impl Debug for LockTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for LockTimeout {}
+
+impl<G> From<PoisonError<G>> for TryLockForError<G> {
+    fn from(err: PoisonError<G>) -> TryLockForError<G> {
+        TryLockForError::Poisoned(err)
+    }
+}
+
+impl<G> Debug for TryLockForError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryLockForError::Poisoned(..) => f.write_str("Poisoned(..)"),
+            TryLockForError::Timeout(timeout) => f.debug_tuple("Timeout").field(timeout).finish(),
+        }
+    }
+}
+
+impl<G> Display for TryLockForError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryLockForError::Poisoned(poison) => Display::fmt(poison, f),
+            TryLockForError::Timeout(timeout) => Display::fmt(timeout, f),
+        }
+    }
+}
+
+impl<G> Error for TryLockForError<G> {}
@@ -0,0 +1,610 @@
+use crate::debug_symbols::capture_caller_stack;
+use crate::debugging_locks::{
+    diagnostics_enabled, record_would_block, stack_created_if_enabled, wait_registered, LIB_VERSION,
+};
+use crate::lock_events::{LockAccess, LockEventHandler};
+use crate::lock_order;
+use crate::lock_registry::{HolderToken, LockState};
+use core::fmt;
+use log::info;
+use parking_lot::{
+    Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockUpgradableReadGuard, RwLockWriteGuard,
+};
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// wrappers for parking_lot::RwLock and parking_lot::Mutex (feature "parking_lot")
+// waiters block on the real lock (or until the deadline of try_*_for); the watchdog reports them meanwhile
+
+/// parking_lot counterpart of [`crate::debugging_locks::RwLockWrapped`]; no poisoning.
+pub struct ParkingLotRwLockWrapped<T: ?Sized> {
+    // creation stack, current holders and waiters
+    state: Arc<LockState>,
+    // RwLock must be last element in struct
+    inner: RwLock<T>,
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ParkingLotRwLockWrapped<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T> ParkingLotRwLockWrapped<T> {
+    #[track_caller]
+    pub fn new(t: T) -> ParkingLotRwLockWrapped<T> {
+        ParkingLotRwLockWrapped::create(t, None)
+    }
+
+    /// See [`crate::debugging_locks::RwLockWrapped::new_named`].
+    #[track_caller]
+    pub fn new_named(name: &'static str, t: T) -> ParkingLotRwLockWrapped<T> {
+        ParkingLotRwLockWrapped::create(t, Some(name))
+    }
+
+    #[track_caller]
+    fn create(t: T, name: Option<&'static str>) -> ParkingLotRwLockWrapped<T> {
+        info!(
+            "NEW WRAPPED PARKING_LOT RWLOCK {}(v{})",
+            name.map(|name| format!("{} ", name)).unwrap_or_default(),
+            LIB_VERSION
+        );
+        ParkingLotRwLockWrapped {
+            inner: RwLock::new(t),
+            state: LockState::new(stack_created_if_enabled(), name, Location::caller()),
+        }
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        self.state.name
+    }
+
+    /// See [`crate::debugging_locks::RwLockWrapped::with_event_handler`].
+    pub fn with_event_handler(
+        self,
+        handler: Arc<dyn LockEventHandler>,
+    ) -> ParkingLotRwLockWrapped<T> {
+        self.state.set_event_handler(handler);
+        self
+    }
+
+    pub fn to_rwlock(&self) -> &RwLock<T> {
+        &self.inner
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> ParkingLotRwLockReadGuardWrapped<'_, T> {
+        self.try_read_until(None)
+            .expect("acquisition without deadline must not fail")
+    }
+
+    #[track_caller]
+    pub fn try_read(&self) -> Option<ParkingLotRwLockReadGuardWrapped<'_, T>> {
+        let Some(guard) = self.inner.try_read() else {
+            record_would_block(&self.state);
+            return None;
+        };
+        Some(ParkingLotRwLockReadGuardWrapped {
+            holder: register_holder(&self.state, LockAccess::Read),
+            inner: ManuallyDrop::new(guard),
+        })
+    }
+
+    /// Like [`ParkingLotRwLockWrapped::read`] but gives up after `timeout`.
+    #[track_caller]
+    pub fn try_read_for(
+        &self,
+        timeout: Duration,
+    ) -> Option<ParkingLotRwLockReadGuardWrapped<'_, T>> {
+        self.try_read_until(Some(Instant::now() + timeout))
+    }
+
+    #[track_caller]
+    fn try_read_until(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Option<ParkingLotRwLockReadGuardWrapped<'_, T>> {
+        let acquire = || match deadline {
+            None => Some(self.inner.read()),
+            Some(deadline) => self.inner.try_read_until(deadline),
+        };
+        let guard = if !diagnostics_enabled() {
+            acquire()?
+        } else {
+            lock_order::before_acquire(&self.state, Location::caller());
+            match self.inner.try_read() {
+                Some(guard) => guard,
+                None => {
+                    wait_registered(&self.state, LockAccess::Read, Location::caller(), acquire)?
+                }
+            }
+        };
+        Some(ParkingLotRwLockReadGuardWrapped {
+            holder: register_holder(&self.state, LockAccess::Read),
+            inner: ManuallyDrop::new(guard),
+        })
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> ParkingLotRwLockWriteGuardWrapped<'_, T> {
+        self.try_write_until(None)
+            .expect("acquisition without deadline must not fail")
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> Option<ParkingLotRwLockWriteGuardWrapped<'_, T>> {
+        let Some(guard) = self.inner.try_write() else {
+            record_would_block(&self.state);
+            return None;
+        };
+        Some(ParkingLotRwLockWriteGuardWrapped {
+            holder: register_holder(&self.state, LockAccess::Write),
+            inner: ManuallyDrop::new(guard),
+        })
+    }
+
+    /// Like [`ParkingLotRwLockWrapped::write`] but gives up after `timeout`.
+    #[track_caller]
+    pub fn try_write_for(
+        &self,
+        timeout: Duration,
+    ) -> Option<ParkingLotRwLockWriteGuardWrapped<'_, T>> {
+        self.try_write_until(Some(Instant::now() + timeout))
+    }
+
+    #[track_caller]
+    fn try_write_until(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Option<ParkingLotRwLockWriteGuardWrapped<'_, T>> {
+        let acquire = || match deadline {
+            None => Some(self.inner.write()),
+            Some(deadline) => self.inner.try_write_until(deadline),
+        };
+        let guard = if !diagnostics_enabled() {
+            acquire()?
+        } else {
+            lock_order::before_acquire(&self.state, Location::caller());
+            match self.inner.try_write() {
+                Some(guard) => guard,
+                None => {
+                    wait_registered(&self.state, LockAccess::Write, Location::caller(), acquire)?
+                }
+            }
+        };
+        Some(ParkingLotRwLockWriteGuardWrapped {
+            holder: register_holder(&self.state, LockAccess::Write),
+            inner: ManuallyDrop::new(guard),
+        })
+    }
+
+    #[track_caller]
+    pub fn upgradable_read(&self) -> ParkingLotRwLockUpgradableReadGuardWrapped<'_, T> {
+        self.try_upgradable_read_until(None)
+            .expect("acquisition without deadline must not fail")
+    }
+
+    #[track_caller]
+    pub fn try_upgradable_read(&self) -> Option<ParkingLotRwLockUpgradableReadGuardWrapped<'_, T>> {
+        let Some(guard) = self.inner.try_upgradable_read() else {
+            record_would_block(&self.state);
+            return None;
+        };
+        Some(ParkingLotRwLockUpgradableReadGuardWrapped {
+            holder: register_holder(&self.state, LockAccess::UpgradableRead),
+            inner: ManuallyDrop::new(guard),
+        })
+    }
+
+    /// Like [`ParkingLotRwLockWrapped::upgradable_read`] but gives up after `timeout`.
+    #[track_caller]
+    pub fn try_upgradable_read_for(
+        &self,
+        timeout: Duration,
+    ) -> Option<ParkingLotRwLockUpgradableReadGuardWrapped<'_, T>> {
+        self.try_upgradable_read_until(Some(Instant::now() + timeout))
+    }
+
+    #[track_caller]
+    fn try_upgradable_read_until(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Option<ParkingLotRwLockUpgradableReadGuardWrapped<'_, T>> {
+        let acquire = || match deadline {
+            None => Some(self.inner.upgradable_read()),
+            Some(deadline) => self.inner.try_upgradable_read_until(deadline),
+        };
+        let guard = if !diagnostics_enabled() {
+            acquire()?
+        } else {
+            lock_order::before_acquire(&self.state, Location::caller());
+            match self.inner.try_upgradable_read() {
+                Some(guard) => guard,
+                None => wait_registered(
+                    &self.state,
+                    LockAccess::UpgradableRead,
+                    Location::caller(),
+                    acquire,
+                )?,
+            }
+        };
+        Some(ParkingLotRwLockUpgradableReadGuardWrapped {
+            holder: register_holder(&self.state, LockAccess::UpgradableRead),
+            inner: ManuallyDrop::new(guard),
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: Default> Default for ParkingLotRwLockWrapped<T> {
+    /// Creates a new `RwLock<T>`, with the `Default` value for T.
+    #[track_caller]
+    fn default() -> ParkingLotRwLockWrapped<T> {
+        ParkingLotRwLockWrapped::new(Default::default())
+    }
+}
+
+/// parking_lot counterpart of [`crate::debugging_locks::MutexWrapped`]; no poisoning.
+pub struct ParkingLotMutexWrapped<T: ?Sized> {
+    // creation stack, current holders and waiters
+    state: Arc<LockState>,
+    // Mutex must be last element in struct
+    inner: Mutex<T>,
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ParkingLotMutexWrapped<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T> ParkingLotMutexWrapped<T> {
+    #[track_caller]
+    pub fn new(t: T) -> ParkingLotMutexWrapped<T> {
+        ParkingLotMutexWrapped::create(t, None)
+    }
+
+    /// See [`crate::debugging_locks::RwLockWrapped::new_named`].
+    #[track_caller]
+    pub fn new_named(name: &'static str, t: T) -> ParkingLotMutexWrapped<T> {
+        ParkingLotMutexWrapped::create(t, Some(name))
+    }
+
+    #[track_caller]
+    fn create(t: T, name: Option<&'static str>) -> ParkingLotMutexWrapped<T> {
+        info!(
+            "NEW WRAPPED PARKING_LOT MUTEX {}(v{})",
+            name.map(|name| format!("{} ", name)).unwrap_or_default(),
+            LIB_VERSION
+        );
+        ParkingLotMutexWrapped {
+            inner: Mutex::new(t),
+            state: LockState::new(stack_created_if_enabled(), name, Location::caller()),
+        }
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        self.state.name
+    }
+
+    /// See [`crate::debugging_locks::RwLockWrapped::with_event_handler`].
+    pub fn with_event_handler(
+        self,
+        handler: Arc<dyn LockEventHandler>,
+    ) -> ParkingLotMutexWrapped<T> {
+        self.state.set_event_handler(handler);
+        self
+    }
+
+    pub fn to_mutex(&self) -> &Mutex<T> {
+        &self.inner
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> ParkingLotMutexGuardWrapped<'_, T> {
+        self.try_lock_until(None)
+            .expect("acquisition without deadline must not fail")
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<ParkingLotMutexGuardWrapped<'_, T>> {
+        let Some(guard) = self.inner.try_lock() else {
+            record_would_block(&self.state);
+            return None;
+        };
+        Some(ParkingLotMutexGuardWrapped {
+            holder: register_holder(&self.state, LockAccess::Lock),
+            inner: ManuallyDrop::new(guard),
+        })
+    }
+
+    /// Like [`ParkingLotMutexWrapped::lock`] but gives up after `timeout`.
+    #[track_caller]
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<ParkingLotMutexGuardWrapped<'_, T>> {
+        self.try_lock_until(Some(Instant::now() + timeout))
+    }
+
+    #[track_caller]
+    fn try_lock_until(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Option<ParkingLotMutexGuardWrapped<'_, T>> {
+        let acquire = || match deadline {
+            None => Some(self.inner.lock()),
+            Some(deadline) => self.inner.try_lock_until(deadline),
+        };
+        let guard = if !diagnostics_enabled() {
+            acquire()?
+        } else {
+            lock_order::before_acquire(&self.state, Location::caller());
+            match self.inner.try_lock() {
+                Some(guard) => guard,
+                None => {
+                    wait_registered(&self.state, LockAccess::Lock, Location::caller(), acquire)?
+                }
+            }
+        };
+        Some(ParkingLotMutexGuardWrapped {
+            holder: register_holder(&self.state, LockAccess::Lock),
+            inner: ManuallyDrop::new(guard),
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: Default> Default for ParkingLotMutexWrapped<T> {
+    /// Creates a new `Mutex<T>`, with the `Default` value for T.
+    #[track_caller]
+    fn default() -> ParkingLotMutexWrapped<T> {
+        ParkingLotMutexWrapped::new(Default::default())
+    }
+}
+
+/// RAII guard of [`ParkingLotRwLockWrapped::read`]; the holder is tracked until the guard is dropped.
+pub struct ParkingLotRwLockReadGuardWrapped<'a, T: ?Sized> {
+    holder: Option<HolderToken<'a>>,
+    // dropped explicitly - see Drop
+    inner: ManuallyDrop<RwLockReadGuard<'a, T>>,
+}
+
+/// RAII guard of [`ParkingLotRwLockWrapped::write`]; the holder is tracked until the guard is dropped.
+pub struct ParkingLotRwLockWriteGuardWrapped<'a, T: ?Sized> {
+    holder: Option<HolderToken<'a>>,
+    // dropped explicitly - see Drop
+    inner: ManuallyDrop<RwLockWriteGuard<'a, T>>,
+}
+
+/// RAII guard of [`ParkingLotRwLockWrapped::upgradable_read`]; see [`ParkingLotRwLockUpgradableReadGuardWrapped::upgrade`].
+pub struct ParkingLotRwLockUpgradableReadGuardWrapped<'a, T: ?Sized> {
+    holder: Option<HolderToken<'a>>,
+    // dropped explicitly - see Drop
+    inner: ManuallyDrop<RwLockUpgradableReadGuard<'a, T>>,
+}
+
+/// RAII guard of [`ParkingLotMutexWrapped::lock`]; the holder is tracked until the guard is dropped.
+pub struct ParkingLotMutexGuardWrapped<'a, T: ?Sized> {
+    holder: Option<HolderToken<'a>>,
+    // dropped explicitly - see Drop
+    inner: ManuallyDrop<MutexGuard<'a, T>>,
+}
+
+impl<'a, T: ?Sized> ParkingLotRwLockUpgradableReadGuardWrapped<'a, T> {
+    /// Atomically upgrade to a write guard; the watchdog reports while waiting for the readers.
+    #[track_caller]
+    pub fn upgrade(s: Self) -> ParkingLotRwLockWriteGuardWrapped<'a, T> {
+        let mut s = ManuallyDrop::new(s);
+        // SAFETY: s is not dropped, thus inner is taken exactly once
+        let upgradable = unsafe { ManuallyDrop::take(&mut s.inner) };
+        let Some(mut holder) = s.holder.take() else {
+            // acquired while diagnostics were disabled
+            return ParkingLotRwLockWriteGuardWrapped {
+                holder: None,
+                inner: ManuallyDrop::new(RwLockUpgradableReadGuard::upgrade(upgradable)),
+            };
+        };
+        let state = holder.state();
+
+        let guard = match RwLockUpgradableReadGuard::try_upgrade(upgradable) {
+            Ok(guard) => guard,
+            Err(upgradable) => {
+                wait_registered(state, LockAccess::Write, Location::caller(), || {
+                    RwLockUpgradableReadGuard::upgrade(upgradable)
+                })
+            }
+        };
+
+        // lock stays held - only the holder changes
+        holder.release(|| {});
+        ParkingLotRwLockWriteGuardWrapped {
+            holder: register_holder(state, LockAccess::Write),
+            inner: ManuallyDrop::new(guard),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for ParkingLotRwLockReadGuardWrapped<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner.deref()
+    }
+}
+
+impl<T: ?Sized> Deref for ParkingLotRwLockWriteGuardWrapped<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner.deref()
+    }
+}
+
+impl<T: ?Sized> DerefMut for ParkingLotRwLockWriteGuardWrapped<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner.deref_mut()
+    }
+}
+
+impl<T: ?Sized> Deref for ParkingLotRwLockUpgradableReadGuardWrapped<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner.deref()
+    }
+}
+
+impl<T: ?Sized> Deref for ParkingLotMutexGuardWrapped<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner.deref()
+    }
+}
+
+impl<T: ?Sized> DerefMut for ParkingLotMutexGuardWrapped<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner.deref_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for ParkingLotRwLockReadGuardWrapped<'_, T> {
+    fn drop(&mut self) {
+        let inner = &mut self.inner;
+        // SAFETY: inner is not accessed after this point
+        match &mut self.holder {
+            Some(holder) => holder.release(|| unsafe { ManuallyDrop::drop(inner) }),
+            None => unsafe { ManuallyDrop::drop(inner) },
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ParkingLotRwLockReadGuardWrapped<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Drop for ParkingLotRwLockWriteGuardWrapped<'_, T> {
+    fn drop(&mut self) {
+        let inner = &mut self.inner;
+        // SAFETY: inner is not accessed after this point
+        match &mut self.holder {
+            Some(holder) => holder.release(|| unsafe { ManuallyDrop::drop(inner) }),
+            None => unsafe { ManuallyDrop::drop(inner) },
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ParkingLotRwLockWriteGuardWrapped<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Drop for ParkingLotRwLockUpgradableReadGuardWrapped<'_, T> {
+    fn drop(&mut self) {
+        let inner = &mut self.inner;
+        // SAFETY: inner is not accessed after this point
+        match &mut self.holder {
+            Some(holder) => holder.release(|| unsafe { ManuallyDrop::drop(inner) }),
+            None => unsafe { ManuallyDrop::drop(inner) },
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ParkingLotRwLockUpgradableReadGuardWrapped<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Drop for ParkingLotMutexGuardWrapped<'_, T> {
+    fn drop(&mut self) {
+        let inner = &mut self.inner;
+        // SAFETY: inner is not accessed after this point
+        match &mut self.holder {
+            Some(holder) => holder.release(|| unsafe { ManuallyDrop::drop(inner) }),
+            None => unsafe { ManuallyDrop::drop(inner) },
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ParkingLotMutexGuardWrapped<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+// None while diagnostics are disabled: no stack capture, the guard just unlocks
+#[track_caller]
+fn register_holder(state: &LockState, access: LockAccess) -> Option<HolderToken<'_>> {
+    if !diagnostics_enabled() {
+        return None;
+    }
+    Some(state.register_holder(access, Location::caller(), capture_caller_stack()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn upgrade_replaces_upgradable_holder_by_write_holder() {
+        let lock = ParkingLotRwLockWrapped::new(42);
+        let upgradable = lock.upgradable_read();
+        let write = ParkingLotRwLockUpgradableReadGuardWrapped::upgrade(upgradable);
+
+        let holders = lock.state.holders();
+        assert_eq!(1, holders.len());
+        assert_eq!(LockAccess::Write, holders[0].access);
+        drop(write);
+        assert!(lock.state.holders().is_empty());
+    }
+
+    #[test]
+    fn try_lock_for_times_out_without_leftover_waiter() {
+        let lock = Arc::new(ParkingLotMutexWrapped::new(42));
+        let guard = lock.lock();
+        let timed_out = {
+            let lock = lock.clone();
+            thread::spawn(move || lock.try_lock_for(Duration::from_millis(20)).is_none())
+        };
+        assert!(timed_out.join().unwrap());
+        assert!(lock.state.waiters().is_empty());
+
+        drop(guard);
+        assert_eq!(42, *lock.try_lock_for(Duration::from_millis(20)).unwrap());
+    }
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn try_read_write_and_upgradable_read_for_time_out() {
+        let lock = Arc::new(ParkingLotRwLockWrapped::new(42));
+        let write = lock.write();
+        let timed_out = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let timeout = Duration::from_millis(10);
+                lock.try_read_for(timeout).is_none()
+                    && lock.try_write_for(timeout).is_none()
+                    && lock.try_upgradable_read_for(timeout).is_none()
+            })
+        };
+        assert!(timed_out.join().unwrap());
+        assert!(lock.state.waiters().is_empty());
+        assert_eq!(1, lock.state.holders().len());
+
+        drop(write);
+        assert_eq!(42, *lock.try_read_for(Duration::from_millis(10)).unwrap());
+    }
+}
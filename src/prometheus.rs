@@ -0,0 +1,295 @@
+use crate::histogram::Histogram;
+use crate::lock_stats::{lock_stats, LockStats};
+use log::{info, warn};
+use std::fmt::Write as _;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+// Prometheus text exposition format (version 0.0.4) of the lock statistics (see crate::lock_stats);
+// one series per locktag and name, histograms are exposed as summaries
+
+const QUANTILES: [f64; 3] = [0.5, 0.99, 0.999];
+// requests are tiny - anything beyond is not a scrape
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+// a client which does not send its request in time gets dropped
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Render the counters and histograms of all locks (see [`crate::lock_stats::lock_stats`]).
+pub fn render_metrics() -> String {
+    let stats = lock_stats();
+    let mut out = String::new();
+
+    write_family(
+        &mut out,
+        "debugging_locks_acquisitions_total",
+        "counter",
+        "Acquisitions by access (mutex lock counts as write).",
+        &stats,
+        |out, labels, stats| {
+            write_sample(
+                out,
+                "debugging_locks_acquisitions_total",
+                &format!("{},access=\"read\"", labels),
+                stats.read_acquisitions as f64,
+            );
+            write_sample(
+                out,
+                "debugging_locks_acquisitions_total",
+                &format!("{},access=\"write\"", labels),
+                stats.write_acquisitions as f64,
+            );
+        },
+    );
+    write_counter(
+        &mut out,
+        "debugging_locks_contended_total",
+        "Acquisitions which had to wait.",
+        &stats,
+        |stats| stats.contended,
+    );
+    write_counter(
+        &mut out,
+        "debugging_locks_poisonings_total",
+        "Guards dropped while panicking (poisoning the lock).",
+        &stats,
+        |stats| stats.poisonings,
+    );
+    write_counter(
+        &mut out,
+        "debugging_locks_would_block_total",
+        "Failed try_read/try_write/try_lock.",
+        &stats,
+        |stats| stats.would_block,
+    );
+    write_summary(
+        &mut out,
+        "debugging_locks_wait_seconds",
+        "Time spent waiting for the lock.",
+        &stats,
+        |stats| (&stats.wait_histogram, stats.wait_total, stats.wait_max),
+    );
+    write_summary(
+        &mut out,
+        "debugging_locks_hold_seconds",
+        "Time the lock was held.",
+        &stats,
+        |stats| (&stats.hold_histogram, stats.hold_total, stats.hold_max),
+    );
+    out
+}
+
+fn write_family(
+    out: &mut String,
+    metric: &str,
+    metric_type: &str,
+    help: &str,
+    stats: &[LockStats],
+    write_samples: impl Fn(&mut String, &str, &LockStats),
+) {
+    let _ = writeln!(out, "# HELP {} {}", metric, help);
+    let _ = writeln!(out, "# TYPE {} {}", metric, metric_type);
+    for stats in stats {
+        write_samples(out, &lock_labels(stats), stats);
+    }
+}
+
+fn write_counter(
+    out: &mut String,
+    metric: &str,
+    help: &str,
+    stats: &[LockStats],
+    value: impl Fn(&LockStats) -> u64,
+) {
+    write_family(out, metric, "counter", help, stats, |out, labels, stats| {
+        write_sample(out, metric, labels, value(stats) as f64)
+    });
+}
+
+// quantiles, sum and count of the histogram plus a gauge "<metric>_max"
+fn write_summary(
+    out: &mut String,
+    metric: &str,
+    help: &str,
+    stats: &[LockStats],
+    value: impl Fn(&LockStats) -> (&Histogram, Duration, Duration),
+) {
+    write_family(out, metric, "summary", help, stats, |out, labels, stats| {
+        let (histogram, total, _) = value(stats);
+        for quantile in QUANTILES {
+            write_sample(
+                out,
+                metric,
+                &format!("{},quantile=\"{}\"", labels, quantile),
+                histogram.quantile(quantile).as_secs_f64(),
+            );
+        }
+        write_sample(out, &format!("{}_sum", metric), labels, total.as_secs_f64());
+        write_sample(
+            out,
+            &format!("{}_count", metric),
+            labels,
+            histogram.count() as f64,
+        );
+    });
+    let metric_max = format!("{}_max", metric);
+    write_family(
+        out,
+        &metric_max,
+        "gauge",
+        &format!("Maximum of {}.", metric),
+        stats,
+        |out, labels, stats| {
+            let (_, _, max) = value(stats);
+            write_sample(out, &metric_max, labels, max.as_secs_f64());
+        },
+    );
+}
+
+fn write_sample(out: &mut String, metric: &str, labels: &str, value: f64) {
+    let _ = writeln!(out, "{}{{{}}} {}", metric, labels, value);
+}
+
+// unnamed locks get an empty name label
+fn lock_labels(stats: &LockStats) -> String {
+    format!(
+        "locktag=\"{}\",name=\"{}\"",
+        escape_label_value(&stats.locktag),
+        escape_label_value(stats.name.unwrap_or_default())
+    )
+}
+
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Serve [`render_metrics`] as `GET /metrics` on `127.0.0.1:port` from a background thread.
+///
+/// Port 0 picks a free port; returns the bound address. Each connection is handled on its own thread,
+/// i.e. a stalled client does not block other scrapes.
+pub fn start_metrics_server(port: u16) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    let addr = listener.local_addr()?;
+    thread::Builder::new()
+        .name("lock-metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let spawned = stream.and_then(|stream| {
+                    thread::Builder::new()
+                        .name("lock-metrics-connection".to_string())
+                        .spawn(move || {
+                            if let Err(error) = handle_connection(stream) {
+                                warn!("Failed to serve lock metrics: {}", error);
+                            }
+                        })
+                });
+                if let Err(error) = spawned {
+                    warn!("Failed to serve lock metrics: {}", error);
+                }
+            }
+        })?;
+    info!("Serving lock metrics on http://{}/metrics", addr);
+    Ok(addr)
+}
+
+fn handle_connection(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let request = read_request_head(&mut stream)?;
+    let request_line = request.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render_metrics()),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+// request line and headers; the body (if any) is ignored
+fn read_request_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugging_locks::MutexWrapped;
+    use std::time::Instant;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn escape_labels() {
+        assert_eq!(r#"a\"b\\c\nd"#, escape_label_value("a\"b\\c\nd"));
+    }
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn serve_metrics_on_localhost() {
+        let mutex = MutexWrapped::new_named("prometheus_test", 0);
+        drop(mutex.lock().unwrap());
+        let addr = start_metrics_server(0).unwrap();
+
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("# TYPE debugging_locks_hold_seconds summary"));
+        let labels = "name=\"prometheus_test\",access=\"write\"";
+        let acquisitions = response
+            .lines()
+            .find(|line| line.contains(labels))
+            .expect("series of the named lock");
+        assert!(acquisitions.ends_with(" 1"), "{}", acquisitions);
+
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn stalled_connection_does_not_block_scrapes() {
+        let addr = start_metrics_server(0).unwrap();
+        // connected, but never sends a request
+        let _stalled = TcpStream::connect(addr).unwrap();
+
+        let since = Instant::now();
+        assert!(get(addr, "/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(since.elapsed() < READ_TIMEOUT);
+    }
+}
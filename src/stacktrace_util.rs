@@ -1,20 +1,34 @@
+use crate::debug_symbols;
+use crate::thresholds_config;
 use base58::ToBase58;
-use log::{debug, log, Level};
+use log::{log, Level};
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::ffi::c_void;
 use std::fmt;
 use std::fmt::Display;
-use std::hash::{Hash, Hasher};
+use std::hash::Hasher;
+use std::panic::Location;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
 use std::thread::ThreadId;
 
+#[derive(serde_derive::Serialize)]
 pub struct Stracktrace {
     pub frames: Vec<Frame>,
     // simple tagging of stacktrace e.g. 'JuCPL' - use for grepping
     pub hash: String,
 }
 
+#[derive(serde_derive::Serialize)]
 pub struct Frame {
+    // demangled without hash suffix and generic parameters, e.g. "simple::writer_blocks_reader"
     pub method: String,
+    // raw symbol name, e.g. "simple::writer_blocks_reader::h90b32e8be4ee69f9"
+    pub symbol: String,
     pub filename: String,
     pub line_no: u32,
 }
@@ -24,12 +38,22 @@ pub struct ThreadInfo {
     pub name: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BacktrackError {
     NoStartFrame,
     NoDebugSymbols,
 }
 
+impl ThreadInfo {
+    pub fn current() -> ThreadInfo {
+        let thread = std::thread::current();
+        ThreadInfo {
+            thread_id: thread.id(),
+            name: thread.name().unwrap_or("no_thread").to_string(),
+        }
+    }
+}
+
 impl Display for ThreadInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // TODO fix format "main:ThreadId(1)" -> how to deal with numeric thread id?
@@ -37,6 +61,24 @@ impl Display for ThreadInfo {
     }
 }
 
+// {"name": "main", "id": 1}
+impl Serialize for ThreadInfo {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // ThreadId::as_u64 is unstable - parse "ThreadId(1)"
+        let id: String = format!("{:?}", self.thread_id)
+            .chars()
+            .filter(|c| c.is_ascii_digit())
+            .collect();
+        let mut state = serializer.serialize_struct("ThreadInfo", 2)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("id", &id.parse::<u64>().ok())?;
+        state.end()
+    }
+}
+
 impl Display for BacktrackError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -59,108 +101,327 @@ impl std::error::Error for BacktrackError {}
 /// let frames = backtrack_frame(|symbol_name| symbol_name.starts_with("rust_basics::debugging_locks::"));
 /// ```
 pub fn backtrack_frame(fn_skip_frame: fn(&str) -> bool) -> Result<Stracktrace, BacktrackError> {
-    const FRAMES_LIMIT: usize = 99;
+    let mut collector = FrameCollector::new(fn_skip_frame);
 
-    let mut started = false;
-    let mut stop = false;
-    let mut symbols = 0;
-    let mut hasher = DefaultHasher::new();
+    backtrace::trace(|frame| {
+        for symbol in resolve_cached(frame.ip() as usize).iter() {
+            collector.add_symbol(symbol);
+        }
+        !collector.stop
+    });
+
+    collector.finish()
+}
+
+// symbol data required by FrameCollector; one instruction pointer may resolve to several (inlined) symbols
+struct CachedSymbol {
+    name: Option<String>,
+    // see clean_method_name
+    method: Option<String>,
+    filename: Option<PathBuf>,
+    line_no: Option<u32>,
+}
+
+/// Counters of the global symbol cache (see [`symbol_cache_stats`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SymbolCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+// instruction pointer -> resolved symbols
+static SYMBOL_CACHE: RwLock<Option<HashMap<usize, Arc<[CachedSymbol]>>>> = RwLock::new(None);
+static SYMBOL_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static SYMBOL_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+// bounds memory if code addresses are unbounded (e.g. JIT); further misses do not get cached
+const SYMBOL_CACHE_LIMIT: usize = 100_000;
+
+fn resolve_cached(ip: usize) -> Arc<[CachedSymbol]> {
+    if let Some(symbols) = SYMBOL_CACHE
+        .read()
+        .unwrap()
+        .as_ref()
+        .and_then(|cache| cache.get(&ip))
+    {
+        SYMBOL_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+        return symbols.clone();
+    }
+    SYMBOL_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+
+    // note: resolve without holding the cache lock - reading the debug info is slow
+    let mut symbols = vec![];
+    backtrace::resolve(ip as *mut c_void, |symbol| {
+        symbols.push(CachedSymbol {
+            name: symbol.name().map(|name| name.to_string()),
+            // alternate format omits the hash suffix
+            method: symbol
+                .name()
+                .map(|name| clean_method_name(&format!("{:#}", name))),
+            filename: symbol.filename().map(|filename| filename.to_path_buf()),
+            line_no: symbol.lineno(),
+        });
+    });
+    let symbols: Arc<[CachedSymbol]> = symbols.into();
+
+    let mut cache = SYMBOL_CACHE.write().unwrap();
+    let cache = cache.get_or_insert_with(HashMap::new);
+    if cache.len() < SYMBOL_CACHE_LIMIT {
+        cache.insert(ip, symbols.clone());
+    }
+    symbols
+}
+
+/// Hit/miss counters of the symbol cache shared by [`backtrack_frame`] and [`UnresolvedStracktrace::resolve`].
+pub fn symbol_cache_stats() -> SymbolCacheStats {
+    SymbolCacheStats {
+        hits: SYMBOL_CACHE_HITS.load(Ordering::Relaxed),
+        misses: SYMBOL_CACHE_MISSES.load(Ordering::Relaxed),
+        entries: SYMBOL_CACHE
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|cache| cache.len())
+            .unwrap_or(0),
+    }
+}
+
+/// Drop all cached symbols; counters are not reset.
+pub fn clear_symbol_cache() {
+    *SYMBOL_CACHE.write().unwrap() = None;
+}
 
+// shared by eager (backtrack_frame) and lazy (UnresolvedStracktrace::resolve) symbolization
+struct FrameCollector {
+    fn_skip_frame: fn(&str) -> bool,
+    started: bool,
+    stop: bool,
+    symbols: u32,
+    hasher: StableHasher,
     // ordering: inside out
-    let mut frames: Vec<Frame> = vec![];
+    frames: Vec<Frame>,
+}
 
-    backtrace::trace(|frame| {
-        backtrace::resolve_frame(frame, |symbol| {
-            // note: values are None for release build
-            // sample output:
-            // Symbol { name: backtrace::backtrace::trace_unsynchronized::hc02a5cecd085adce,
-            //   addr: 0x100001b2a, filename: ".../.cargo/registry/src/github.com-1ecc6299db9ec823/backtrace-0.3.67/src/backtrace/mod.rs", lineno: 66 }
-
-            if stop {
-                return;
-            }
+impl FrameCollector {
+    const FRAMES_LIMIT: usize = 99;
 
-            if symbol.filename().is_none() {
-                return;
-            }
+    fn new(fn_skip_frame: fn(&str) -> bool) -> FrameCollector {
+        FrameCollector {
+            fn_skip_frame,
+            started: false,
+            stop: false,
+            symbols: 0,
+            hasher: StableHasher::new(),
+            frames: vec![],
+        }
+    }
 
-            symbols += 1;
+    fn add_symbol(&mut self, symbol: &CachedSymbol) {
+        // note: values are None for release build
+        // sample output:
+        // Symbol { name: backtrace::backtrace::trace_unsynchronized::hc02a5cecd085adce,
+        //   addr: 0x100001b2a, filename: ".../.cargo/registry/src/github.com-1ecc6299db9ec823/backtrace-0.3.67/src/backtrace/mod.rs", lineno: 66 }
 
-            if frames.len() > FRAMES_LIMIT {
-                stop = true;
-                return;
-            }
+        if self.stop {
+            return;
+        }
 
-            // /rustc/69f9c33d71c871fc16ac445211281c6e7a340943/library/std/src/rt.rs
-            if symbol
-                .filename()
-                .unwrap()
-                .starts_with(PathBuf::from("/rustc"))
-            {
-                stop = true;
-                return;
-            }
+        let filename = match &symbol.filename {
+            None => return,
+            Some(filename) => filename,
+        };
 
-            // symbol.name looks like this "rust_basics::debugging_lock_newtype::backtrack::h1cb6032f9b10548c"
-            let symbol_name = symbol.name().unwrap().to_string();
-            // module_path is "rust_debugging_locks::stacktrace_util"
+        self.symbols += 1;
 
-            if !symbol_name.starts_with("backtrace::backtrace::")
-                && !fn_skip_frame(symbol_name.as_str())
-            {
-                started = true;
-                // do not return to catch the current frame
-            }
+        if self.frames.len() > Self::FRAMES_LIMIT {
+            self.stop = true;
+            return;
+        }
 
-            if !started {
-                return;
-            }
+        // /rustc/69f9c33d71c871fc16ac445211281c6e7a340943/library/std/src/rt.rs
+        if filename.starts_with("/rustc") {
+            self.stop = true;
+            return;
+        }
 
-            let frame = Frame {
-                method: symbol.name().unwrap().to_string(),
-                filename: symbol
-                    .filename()
-                    .unwrap()
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_string(),
-                line_no: symbol.lineno().unwrap(),
-            };
-
-            // hash frame data
-            hasher.write(frame.method.as_bytes());
-            hasher.write_i32(0x2A66ED); // random separator
-            hasher.write(frame.filename.as_bytes());
-            hasher.write_i32(0x2A66ED); // random separator
-            hasher.write_u32(frame.line_no);
-            hasher.write_i32(0xF122ED); // random separator
-
-            frames.push(frame);
-        });
+        // symbol.name looks like this "rust_basics::debugging_lock_newtype::backtrack::h1cb6032f9b10548c"
+        let symbol_name = symbol.name.clone().unwrap();
+        // module_path is "rust_debugging_locks::stacktrace_util"
 
-        !stop
-    });
+        if !symbol_name.starts_with("backtrace::backtrace::")
+            && !(self.fn_skip_frame)(symbol_name.as_str())
+        {
+            self.started = true;
+            // do not return to catch the current frame
+        }
+
+        if !self.started {
+            return;
+        }
 
-    if !started {
-        if symbols == 0 {
-            // detected implicitly by checking frames
-            return Err(BacktrackError::NoDebugSymbols);
+        let frame = Frame {
+            method: symbol
+                .method
+                .clone()
+                .unwrap_or_else(|| clean_method_name(&symbol_name)),
+            symbol: symbol_name,
+            filename: filename.file_name().unwrap().to_str().unwrap().to_string(),
+            line_no: symbol.line_no.unwrap(),
+        };
+
+        self.hasher.write_frame(&frame);
+
+        self.frames.push(frame);
+    }
+
+    fn finish(self) -> Result<Stracktrace, BacktrackError> {
+        if !self.started {
+            if self.symbols == 0 {
+                // detected implicitly by checking frames
+                Err(BacktrackError::NoDebugSymbols)
+            } else {
+                Err(BacktrackError::NoStartFrame)
+            }
         } else {
-            return Err(BacktrackError::NoStartFrame);
+            let hash = locktag_from_hash(self.hasher.finish(), thresholds_config::locktag_length());
+            Ok(Stracktrace {
+                frames: self.frames,
+                hash,
+            })
+        }
+    }
+}
+
+// stable hash of the resolved frames; the same across builds, Rust versions and platforms as long as
+// method names (without hash suffix), file names and line numbers do not change
+// - 64 bit FNV-1a
+// - per frame: method, 0xFF, filename, 0xFF, line_no (4 bytes little endian), 0xFE
+// (0xFF and 0xFE never occur in UTF-8)
+struct StableHasher {
+    state: u64,
+}
+
+impl StableHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    fn new() -> StableHasher {
+        StableHasher {
+            state: Self::OFFSET_BASIS,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= *byte as u64;
+            self.state = self.state.wrapping_mul(Self::PRIME);
         }
-    } else {
-        let hash32 = hasher.finish() as u32;
-        let hash = hash32.to_be_bytes().to_base58();
-        return Ok(Stracktrace { frames, hash });
+    }
+
+    fn write_frame(&mut self, frame: &Frame) {
+        self.write(frame.method.as_bytes());
+        self.write(&[0xFF]);
+        self.write(frame.filename.as_bytes());
+        self.write(&[0xFF]);
+        self.write(&frame.line_no.to_le_bytes());
+        self.write(&[0xFE]);
+    }
+
+    fn finish(&self) -> u64 {
+        self.state
+    }
+}
+
+/// Last `length` characters (1..=11) of the base58 encoded hash; the low digits are evenly distributed.
+pub fn locktag_from_hash(hash: u64, length: usize) -> String {
+    let locktag = hash.to_be_bytes().to_base58();
+    let length = length.clamp(1, MAX_LOCKTAG_LENGTH).min(locktag.len());
+    locktag[locktag.len() - length..].to_string()
+}
+
+/// Locktag of a lock without creation stack (e.g. no debug symbols): stable hash of file, line and column.
+pub fn locktag_from_location(location: &Location) -> String {
+    let mut hasher = StableHasher::new();
+    hasher.write(location.file().as_bytes());
+    hasher.write(&[0xFF]);
+    hasher.write(&location.line().to_le_bytes());
+    hasher.write(&location.column().to_le_bytes());
+    locktag_from_hash(hasher.finish(), thresholds_config::locktag_length())
+}
+
+// base58 of u64::MAX
+pub(crate) const MAX_LOCKTAG_LENGTH: usize = 11;
+
+/// Stack captured as raw instruction pointers; symbolized on first call of [`UnresolvedStracktrace::resolve`].
+///
+/// Capturing is much cheaper than [`backtrack_frame`] as no debug info is read.
+pub struct UnresolvedStracktrace {
+    // ordering: inside out
+    ips: Vec<usize>,
+    // hash of the instruction pointers; only stable within the same process
+    address_hash: u64,
+    fn_skip_frame: fn(&str) -> bool,
+    resolved: OnceLock<Result<Stracktrace, BacktrackError>>,
+}
+
+impl UnresolvedStracktrace {
+    pub fn address_hash(&self) -> u64 {
+        self.address_hash
+    }
+
+    /// Symbolize the stack; the result is cached.
+    ///
+    /// A failure is counted once (see [`crate::debug_symbols::stack_capture_failures`]); without debug
+    /// symbols it got counted on capture already.
+    pub fn resolve(&self) -> Result<&Stracktrace, BacktrackError> {
+        self.resolved
+            .get_or_init(|| {
+                let mut collector = FrameCollector::new(self.fn_skip_frame);
+                for ip in &self.ips {
+                    for symbol in resolve_cached(*ip).iter() {
+                        collector.add_symbol(symbol);
+                    }
+                    if collector.stop {
+                        break;
+                    }
+                }
+                collector.finish().inspect_err(|error| {
+                    if !debug_symbols::debug_symbols_missing() {
+                        debug_symbols::record_capture_failure(*error);
+                    }
+                })
+            })
+            .as_ref()
+            .map_err(|err| *err)
     }
 }
 
-fn debug_frames(frames: &Result<Vec<Frame>, BacktrackError>) {
-    for frame in frames.as_ref().unwrap() {
-        println!("\t>{}:{}:{}", frame.filename, frame.method, frame.line_no);
+/// Capture the instruction pointers of the current stack; see [`UnresolvedStracktrace`].
+pub fn capture_unresolved(
+    fn_skip_frame: fn(&str) -> bool,
+) -> Result<UnresolvedStracktrace, BacktrackError> {
+    const IPS_LIMIT: usize = 128;
+
+    let mut ips: Vec<usize> = Vec::with_capacity(32);
+    let mut hasher = DefaultHasher::new();
+    backtrace::trace(|frame| {
+        let ip = frame.ip() as usize;
+        hasher.write_usize(ip);
+        ips.push(ip);
+        ips.len() < IPS_LIMIT
+    });
+
+    if ips.is_empty() {
+        // unable to walk the stack
+        return Err(BacktrackError::NoStartFrame);
     }
+
+    Ok(UnresolvedStracktrace {
+        ips,
+        address_hash: hasher.finish(),
+        fn_skip_frame,
+        resolved: OnceLock::new(),
+    })
 }
 
 pub fn log_frames(level: Level, msg: &str, stacktrace: &Stracktrace) {
@@ -177,15 +438,52 @@ pub fn log_frames(level: Level, msg: &str, stacktrace: &Stracktrace) {
 }
 
 pub fn get_current_stracktrace() -> Result<Stracktrace, BacktrackError> {
+    backtrack_frame(is_library_frame)
+}
+
+/// Unresolved variant of [`get_current_stracktrace`].
+pub fn get_current_stracktrace_unresolved() -> Result<UnresolvedStracktrace, BacktrackError> {
+    capture_unresolved(is_library_frame)
+}
+
+/// Strip the hash suffix and generic parameters from a demangled symbol name.
+///
+/// e.g. `tokio::runtime::task::core::Core<T,S>::poll::h1933d1f90c9f8006` -> `tokio::runtime::task::core::Core::poll`;
+/// qualified paths like `<Foo as Bar>::baz` are kept.
+pub fn clean_method_name(demangled: &str) -> String {
+    let without_hash = match demangled.rsplit_once("::h") {
+        Some((prefix, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            prefix
+        }
+        _ => demangled,
+    };
+
+    let mut cleaned = String::with_capacity(without_hash.len());
+    // nesting level of skipped generic parameters
+    let mut generic_depth = 0;
+    for c in without_hash.chars() {
+        match c {
+            '<' if generic_depth > 0 => generic_depth += 1,
+            // generic parameters follow an identifier; otherwise it's a qualified path
+            '<' if cleaned.ends_with(|p: char| p.is_alphanumeric() || p == '_') => {
+                generic_depth = 1
+            }
+            '>' if generic_depth > 0 => generic_depth -= 1,
+            _ if generic_depth > 0 => {}
+            _ => cleaned.push(c),
+        }
+    }
+    cleaned
+}
+
+fn is_library_frame(symbol_name: &str) -> bool {
     // covers:
     // rust_debugging_locks::debugging_locks::
     // rust_debugging_locks::stacktrace_util::
     const OMIT_FRAME_SUFFIX1: &str = "rust_debugging_locks:";
     // <rust_debugging_locks::debugging_locks::RwLockWrapped<T> as core::default::Default>::default::haed7701ba5f48aa2:97
     const OMIT_FRAME_SUFFIX2: &str = "<rust_debugging_locks:";
-    backtrack_frame(|symbol_name| {
-        symbol_name.starts_with(OMIT_FRAME_SUFFIX1) || symbol_name.starts_with(OMIT_FRAME_SUFFIX2)
-    })
+    symbol_name.starts_with(OMIT_FRAME_SUFFIX1) || symbol_name.starts_with(OMIT_FRAME_SUFFIX2)
 }
 
 #[derive(Debug, Clone)]
@@ -199,6 +497,12 @@ impl AllocationTracker {
     }
 }
 
+impl Default for AllocationTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -208,15 +512,69 @@ mod tests {
         tracing_subscriber::fmt::init();
         let stacktrace = caller_function().unwrap();
         log_frames(Level::Info, "stacktrace_from_method", &stacktrace);
+        let frame = stacktrace.frames.first().unwrap();
+        assert_eq!(
+            "rust_debugging_locks::stacktrace_util::tests::caller_function",
+            frame.method
+        );
         assert!(
-            stacktrace
-                .frames
-                .get(0)
-                .unwrap()
-                .method
+            frame
+                .symbol
                 .starts_with("rust_debugging_locks::stacktrace_util::tests::caller_function::h"),
-            "method name: {}",
-            stacktrace.frames.get(0).unwrap().method
+            "symbol name: {}",
+            frame.symbol
+        );
+    }
+
+    #[test]
+    fn stable_hash_of_frames() {
+        let frame = Frame {
+            method: "simple::main".to_string(),
+            symbol: "simple::main::h51d8a2c7c463da66".to_string(),
+            filename: "simple.rs".to_string(),
+            line_no: 12,
+        };
+        let mut hasher = StableHasher::new();
+        hasher.write_frame(&frame);
+        let hash = hasher.finish();
+
+        // must never change - locktags are compared across releases
+        assert_eq!(0xddd9b7bad3e11d67, hash);
+        assert_eq!(6, locktag_from_hash(hash, 6).len());
+        assert!(locktag_from_hash(hash, 11).ends_with(&locktag_from_hash(hash, 6)));
+        assert_eq!(locktag_from_hash(hash, 11), locktag_from_hash(hash, 99));
+    }
+
+    #[test]
+    fn repeated_capture_hits_symbol_cache() {
+        let first = caller_function().unwrap();
+        let stats_before = symbol_cache_stats();
+        let second = caller_function().unwrap();
+        let stats_after = symbol_cache_stats();
+
+        assert!(stats_after.hits > stats_before.hits);
+        assert_eq!(first.frames[0].method, second.frames[0].method);
+    }
+
+    #[test]
+    fn clean_method_names() {
+        assert_eq!(
+            "simple::writer_blocks_reader",
+            clean_method_name("simple::writer_blocks_reader::h90b32e8be4ee69f9")
+        );
+        assert_eq!(
+            "tokio::runtime::task::core::Core::poll::{{closure}}",
+            clean_method_name("tokio::runtime::task::core::Core<T,S>::poll::{{closure}}")
+        );
+        assert_eq!(
+            "<rust_debugging_locks::debugging_locks::RwLockWrapped as core::default::Default>::default",
+            clean_method_name(
+                "<rust_debugging_locks::debugging_locks::RwLockWrapped<T> as core::default::Default>::default::haed7701ba5f48aa2"
+            )
+        );
+        assert_eq!(
+            "alloc::vec::Vec::push",
+            clean_method_name("alloc::vec::Vec<Option<u8>>::push")
         );
     }
 
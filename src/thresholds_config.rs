@@ -1,25 +1,331 @@
-use std::thread;
+use crate::debug_symbols::DebugSymbolsPolicy;
+use crate::stacktrace_util::MAX_LOCKTAG_LENGTH;
+use crate::watchdog;
+use log::warn;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Once, RwLock};
 use std::time::Duration;
 
-// threshold is based an ordered sequence of sleeps identifed by count (cnt)
-// e.g. 0...1...2...3.......4.......5.......6
+// thresholds are based on the time a waiter is blocked (elapsed)
+// e.g. 0...report_after...report_interval...report_interval...
 
-const SAMPLING_RATE_STAGE1: Duration = Duration::from_micros(100);
-const SAMPLING_RATE_STAGE2: Duration = Duration::from_millis(10);
-const SAMPLING_RATE_STAGE3: Duration = Duration::from_millis(100);
+/// Thresholds for reporting; configure via [`set_thresholds_config`] or env variables.
+///
+/// | env variable | field |
+/// |---|---|
+/// | `DEBUGGING_LOCKS_REPORT_AFTER_MS` | `report_after` |
+/// | `DEBUGGING_LOCKS_REPORT_INTERVAL_MS` | `report_interval` |
+/// | `DEBUGGING_LOCKS_HELD_TOO_LONG_MS` | `held_too_long` (0 disables) |
+/// | `DEBUGGING_LOCKS_WATCHDOG_INTERVAL_MS` | `watchdog_interval` |
+/// | `DEBUGGING_LOCKS_LOCKTAG_LENGTH` | `locktag_length` |
+/// | `DEBUGGING_LOCKS_DEBUG_SYMBOLS` | `debug_symbols_policy` (`warn`, `location` or `panic`) |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThresholdsConfig {
+    // first blocked event after the waiter was blocked that long
+    pub report_after: Duration,
+    // repeat the blocked event while the waiter is still blocked
+    pub report_interval: Duration,
+    // report guards which live longer (starts the watchdog); None disables the check
+    pub held_too_long: Option<Duration>,
+    // the watchdog checks waiters that often while there are any (resolution of the reports);
+    // applies if the watchdog gets started implicitly; at least 1ms
+    pub watchdog_interval: Duration,
+    // number of characters of locktags and stacktrace hashes (1..=11);
+    // applies to stacktraces captured after the change
+    pub locktag_length: usize,
+    // applied once by debug_symbols::self_check if the binary has no debug symbols
+    pub debug_symbols_policy: DebugSymbolsPolicy,
+}
+
+impl Default for ThresholdsConfig {
+    fn default() -> Self {
+        ThresholdsConfig {
+            report_after: Duration::from_millis(2),
+            report_interval: Duration::from_millis(100),
+            held_too_long: None,
+            watchdog_interval: Duration::from_millis(1),
+            locktag_length: 6,
+            debug_symbols_policy: DebugSymbolsPolicy::WarnOnce,
+        }
+    }
+}
+
+impl ThresholdsConfig {
+    /// Defaults overridden by the `DEBUGGING_LOCKS_*` env variables.
+    pub fn from_env() -> ThresholdsConfig {
+        ThresholdsConfig::from_lookup(|key| std::env::var(key).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> ThresholdsConfig {
+        let millis = |key: &str| -> Option<Duration> {
+            let value = lookup(key)?;
+            match value.trim().parse::<u64>() {
+                Ok(millis) => Some(Duration::from_millis(millis)),
+                Err(_) => {
+                    warn!("Ignoring invalid value for {}: {:?}", key, value);
+                    None
+                }
+            }
+        };
+
+        let mut config = ThresholdsConfig::default();
+        if let Some(report_after) = millis("DEBUGGING_LOCKS_REPORT_AFTER_MS") {
+            config.report_after = report_after;
+        }
+        if let Some(report_interval) = millis("DEBUGGING_LOCKS_REPORT_INTERVAL_MS") {
+            config.report_interval = report_interval;
+        }
+        if let Some(held_too_long) = millis("DEBUGGING_LOCKS_HELD_TOO_LONG_MS") {
+            config.held_too_long = Some(held_too_long).filter(|d| !d.is_zero());
+        }
+        if let Some(watchdog_interval) = millis("DEBUGGING_LOCKS_WATCHDOG_INTERVAL_MS") {
+            config.watchdog_interval = watchdog_interval.max(MIN_WATCHDOG_INTERVAL);
+        }
+        if let Some(value) = lookup("DEBUGGING_LOCKS_LOCKTAG_LENGTH") {
+            match value.trim().parse::<usize>() {
+                Ok(length) => config.locktag_length = length.clamp(1, MAX_LOCKTAG_LENGTH),
+                Err(_) => warn!(
+                    "Ignoring invalid value for DEBUGGING_LOCKS_LOCKTAG_LENGTH: {:?}",
+                    value
+                ),
+            }
+        }
+        if let Some(value) = lookup("DEBUGGING_LOCKS_DEBUG_SYMBOLS") {
+            match value.trim().parse::<DebugSymbolsPolicy>() {
+                Ok(policy) => config.debug_symbols_policy = policy,
+                Err(err) => warn!(
+                    "Ignoring invalid value for DEBUGGING_LOCKS_DEBUG_SYMBOLS: {}",
+                    err
+                ),
+            }
+        }
+        config
+    }
+
+    pub fn with_report_after(mut self, report_after: Duration) -> Self {
+        self.report_after = report_after;
+        self
+    }
+
+    pub fn with_report_interval(mut self, report_interval: Duration) -> Self {
+        self.report_interval = report_interval;
+        self
+    }
+
+    pub fn with_held_too_long(mut self, held_too_long: Option<Duration>) -> Self {
+        self.held_too_long = held_too_long;
+        self
+    }
+
+    pub fn with_watchdog_interval(mut self, watchdog_interval: Duration) -> Self {
+        self.watchdog_interval = watchdog_interval;
+        self
+    }
+
+    pub fn with_locktag_length(mut self, locktag_length: usize) -> Self {
+        self.locktag_length = locktag_length;
+        self
+    }
+
+    pub fn with_debug_symbols_policy(mut self, debug_symbols_policy: DebugSymbolsPolicy) -> Self {
+        self.debug_symbols_policy = debug_symbols_policy;
+        self
+    }
+
+    // `last_report` is the elapsed time of the previous report
+    pub(crate) fn should_report(&self, elapsed: Duration, last_report: Option<Duration>) -> bool {
+        match last_report {
+            None => elapsed >= self.report_after,
+            Some(last_report) => elapsed >= last_report + self.report_interval,
+        }
+    }
+}
+
+static INIT: Once = Once::new();
+static CONFIG: RwLock<Option<Arc<ThresholdsConfig>>> = RwLock::new(None);
+// copy of held_too_long for the release path; 0 means disabled
+static HELD_TOO_LONG_MICROS: AtomicU64 = AtomicU64::new(0);
+// copy of locktag_length for stacktrace capturing
+static LOCKTAG_LENGTH: AtomicUsize = AtomicUsize::new(0);
+// overrides for named locks (see RwLockWrapped::new_named)
+static NAMED_CONFIGS: RwLock<Option<HashMap<String, Arc<ThresholdsConfig>>>> = RwLock::new(None);
+// lets the lookups skip NAMED_CONFIGS
+static HAS_NAMED_CONFIGS: AtomicBool = AtomicBool::new(false);
+
+// env variables are read on first use unless the config was set programmatically
+fn ensure_initialized() {
+    INIT.call_once(|| store_config(ThresholdsConfig::from_env()));
+}
+
+// a zero interval would make the watchdog spin
+pub(crate) const MIN_WATCHDOG_INTERVAL: Duration = Duration::from_millis(1);
+
+fn store_config(mut config: ThresholdsConfig) {
+    config.watchdog_interval = config.watchdog_interval.max(MIN_WATCHDOG_INTERVAL);
+    let held_too_long_micros = config
+        .held_too_long
+        .map(|t| t.as_micros().max(1) as u64)
+        .unwrap_or(0);
+    HELD_TOO_LONG_MICROS.store(held_too_long_micros, Ordering::Relaxed);
+    LOCKTAG_LENGTH.store(config.locktag_length, Ordering::Relaxed);
+    let start_watchdog = config.held_too_long.is_some();
+    let watchdog_interval = config.watchdog_interval;
+    *CONFIG.write().unwrap() = Some(Arc::new(config));
+    // locks still held are reported by the watchdog only
+    // note: may run within INIT - start_watchdog must not read the config
+    if start_watchdog && !cfg!(feature = "passthrough") {
+        watchdog::start_watchdog(watchdog_interval);
+    }
+    watchdog::unpark();
+}
+
+/// Replace the global config (takes precedence over the env variables).
+pub fn set_thresholds_config(config: ThresholdsConfig) {
+    INIT.call_once(|| {});
+    store_config(config);
+}
+
+pub fn thresholds_config() -> Arc<ThresholdsConfig> {
+    ensure_initialized();
+    CONFIG
+        .read()
+        .unwrap()
+        .clone()
+        .expect("config must be initialized")
+}
+
+/// Use `config` for the locks named `name` instead of the global config.
+///
+/// Applies to `report_after`, `report_interval` and `held_too_long`; the other fields are global.
+pub fn set_named_thresholds_config(name: &str, config: ThresholdsConfig) {
+    let start_watchdog = config.held_too_long.is_some();
+    NAMED_CONFIGS
+        .write()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(name.to_string(), Arc::new(config));
+    HAS_NAMED_CONFIGS.store(true, Ordering::Relaxed);
+    if start_watchdog && !cfg!(feature = "passthrough") {
+        watchdog::ensure_started();
+    }
+    watchdog::unpark();
+}
+
+/// Remove the override of [`set_named_thresholds_config`].
+pub fn remove_named_thresholds_config(name: &str) {
+    if let Some(configs) = NAMED_CONFIGS.write().unwrap().as_mut() {
+        configs.remove(name);
+    }
+}
+
+fn named_config(name: Option<&str>) -> Option<Arc<ThresholdsConfig>> {
+    let name = name?;
+    if !HAS_NAMED_CONFIGS.load(Ordering::Relaxed) {
+        return None;
+    }
+    NAMED_CONFIGS.read().unwrap().as_ref()?.get(name).cloned()
+}
+
+/// Config of the lock named `name`; the global config if there is no override.
+pub fn thresholds_config_for(name: Option<&str>) -> Arc<ThresholdsConfig> {
+    named_config(name).unwrap_or_else(thresholds_config)
+}
+
+pub(crate) fn held_too_long_for(name: Option<&str>) -> Option<Duration> {
+    match named_config(name) {
+        None => held_too_long(),
+        Some(config) => config.held_too_long,
+    }
+}
+
+// smallest held_too_long of the global and all named configs; None if all are disabled
+pub(crate) fn min_held_too_long() -> Option<Duration> {
+    let global = held_too_long();
+    if !HAS_NAMED_CONFIGS.load(Ordering::Relaxed) {
+        return global;
+    }
+    let configs = NAMED_CONFIGS.read().unwrap();
+    configs
+        .iter()
+        .flat_map(|configs| configs.values())
+        .filter_map(|config| config.held_too_long)
+        .chain(global)
+        .min()
+}
 
-pub fn sleep_backoff(cnt: u64) {
-    if cnt < 100 {
-        thread::sleep(SAMPLING_RATE_STAGE1);
-        return;
-    } else if cnt < 500 {
-        thread::sleep(SAMPLING_RATE_STAGE2);
-        return;
-    } else {
-        thread::sleep(SAMPLING_RATE_STAGE3);
+pub(crate) fn held_too_long() -> Option<Duration> {
+    ensure_initialized();
+    match HELD_TOO_LONG_MICROS.load(Ordering::Relaxed) {
+        0 => None,
+        micros => Some(Duration::from_micros(micros)),
     }
 }
 
-pub fn should_inspect_lock(cnt: u64) -> bool {
-    (20..25).contains(&cnt) || (500..).contains(&cnt)
+pub(crate) fn locktag_length() -> usize {
+    ensure_initialized();
+    LOCKTAG_LENGTH.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_from_env_variables() {
+        let config = ThresholdsConfig::from_lookup(|key| match key {
+            "DEBUGGING_LOCKS_REPORT_AFTER_MS" => Some("50".to_string()),
+            "DEBUGGING_LOCKS_REPORT_INTERVAL_MS" => Some("not-a-number".to_string()),
+            "DEBUGGING_LOCKS_HELD_TOO_LONG_MS" => Some("1000".to_string()),
+            "DEBUGGING_LOCKS_LOCKTAG_LENGTH" => Some("20".to_string()),
+            "DEBUGGING_LOCKS_DEBUG_SYMBOLS" => Some("location".to_string()),
+            _ => None,
+        });
+
+        assert_eq!(Duration::from_millis(50), config.report_after);
+        assert_eq!(
+            ThresholdsConfig::default().report_interval,
+            config.report_interval
+        );
+        assert_eq!(Some(Duration::from_secs(1)), config.held_too_long);
+        assert_eq!(MAX_LOCKTAG_LENGTH, config.locktag_length);
+        assert_eq!(
+            DebugSymbolsPolicy::LocationOnly,
+            config.debug_symbols_policy
+        );
+    }
+
+    #[test]
+    fn report_after_threshold_then_every_interval() {
+        let config = ThresholdsConfig::default()
+            .with_report_after(Duration::from_millis(10))
+            .with_report_interval(Duration::from_millis(100));
+        let ms = Duration::from_millis;
+
+        assert!(!config.should_report(ms(5), None));
+        assert!(config.should_report(ms(10), None));
+        assert!(!config.should_report(ms(50), Some(ms(10))));
+        assert!(config.should_report(ms(110), Some(ms(10))));
+    }
+
+    #[test]
+    fn named_config_overrides_global() {
+        let config = ThresholdsConfig::default().with_held_too_long(Some(Duration::from_millis(5)));
+        set_named_thresholds_config("named_config_test", config.clone());
+
+        assert_eq!(config, *thresholds_config_for(Some("named_config_test")));
+        assert_eq!(*thresholds_config(), *thresholds_config_for(Some("other")));
+        assert_eq!(*thresholds_config(), *thresholds_config_for(None));
+        assert_eq!(
+            Some(Duration::from_millis(5)),
+            held_too_long_for(Some("named_config_test"))
+        );
+        assert!(min_held_too_long().is_some_and(|d| d <= Duration::from_millis(5)));
+
+        remove_named_thresholds_config("named_config_test");
+        assert_eq!(
+            held_too_long(),
+            held_too_long_for(Some("named_config_test"))
+        );
+    }
 }
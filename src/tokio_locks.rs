@@ -0,0 +1,447 @@
+use crate::debug_symbols::capture_caller_stack;
+use crate::debugging_locks::{diagnostics_enabled, stack_created_if_enabled, LIB_VERSION};
+use crate::lock_events::{LockAccess, LockEventHandler};
+use crate::lock_registry::{HolderToken, LockState, WaiterToken};
+use crate::watchdog;
+use core::fmt;
+use log::info;
+use std::future::Future;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// wrappers for tokio::sync::RwLock and tokio::sync::Mutex (feature "tokio")
+// - waiting tasks are registered as waiters and reported by the watchdog thread; the worker thread never blocks
+// - async waiters are not part of the deadlock detection: it works on threads, not tasks
+// - no lock order validation: guards may move between threads
+
+/// Async counterpart of [`crate::debugging_locks::RwLockWrapped`].
+pub struct TokioRwLockWrapped<T: ?Sized> {
+    // creation stack, current holders
+    state: Arc<LockState>,
+    // RwLock must be last element in struct
+    inner: RwLock<T>,
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TokioRwLockWrapped<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T> TokioRwLockWrapped<T> {
+    #[track_caller]
+    pub fn new(t: T) -> TokioRwLockWrapped<T> {
+        TokioRwLockWrapped::create(t, None)
+    }
+
+    /// See [`crate::debugging_locks::RwLockWrapped::new_named`].
+    #[track_caller]
+    pub fn new_named(name: &'static str, t: T) -> TokioRwLockWrapped<T> {
+        TokioRwLockWrapped::create(t, Some(name))
+    }
+
+    #[track_caller]
+    fn create(t: T, name: Option<&'static str>) -> TokioRwLockWrapped<T> {
+        info!(
+            "NEW WRAPPED TOKIO RWLOCK {}(v{})",
+            name.map(|name| format!("{} ", name)).unwrap_or_default(),
+            LIB_VERSION
+        );
+        TokioRwLockWrapped {
+            inner: RwLock::new(t),
+            state: LockState::new(stack_created_if_enabled(), name, Location::caller()),
+        }
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        self.state.name
+    }
+
+    /// See [`crate::debugging_locks::RwLockWrapped::with_event_handler`].
+    pub fn with_event_handler(self, handler: Arc<dyn LockEventHandler>) -> TokioRwLockWrapped<T> {
+        self.state.set_event_handler(handler);
+        self
+    }
+
+    pub fn to_rwlock(&self) -> &RwLock<T> {
+        &self.inner
+    }
+
+    // not an async fn: the caller location must be captured before the future gets polled
+    #[track_caller]
+    pub fn read(&self) -> impl Future<Output = TokioRwLockReadGuardWrapped<'_, T>> {
+        let location = Location::caller();
+        async move {
+            let guard = match self.inner.try_read() {
+                Ok(guard) => guard,
+                Err(_) if !diagnostics_enabled() => self.inner.read().await,
+                Err(_) => {
+                    acquire_reporting(&self.state, LockAccess::Read, location, self.inner.read())
+                        .await
+                }
+            };
+            TokioRwLockReadGuardWrapped {
+                holder: register_holder(&self.state, LockAccess::Read, location),
+                inner: ManuallyDrop::new(guard),
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> impl Future<Output = TokioRwLockWriteGuardWrapped<'_, T>> {
+        let location = Location::caller();
+        async move {
+            let guard = match self.inner.try_write() {
+                Ok(guard) => guard,
+                Err(_) if !diagnostics_enabled() => self.inner.write().await,
+                Err(_) => {
+                    acquire_reporting(&self.state, LockAccess::Write, location, self.inner.write())
+                        .await
+                }
+            };
+            TokioRwLockWriteGuardWrapped {
+                holder: register_holder(&self.state, LockAccess::Write, location),
+                inner: ManuallyDrop::new(guard),
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: Default> Default for TokioRwLockWrapped<T> {
+    /// Creates a new `RwLock<T>`, with the `Default` value for T.
+    #[track_caller]
+    fn default() -> TokioRwLockWrapped<T> {
+        TokioRwLockWrapped::new(Default::default())
+    }
+}
+
+/// Async counterpart of [`crate::debugging_locks::MutexWrapped`].
+pub struct TokioMutexWrapped<T: ?Sized> {
+    // creation stack, current holders
+    state: Arc<LockState>,
+    // Mutex must be last element in struct
+    inner: Mutex<T>,
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TokioMutexWrapped<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T> TokioMutexWrapped<T> {
+    #[track_caller]
+    pub fn new(t: T) -> TokioMutexWrapped<T> {
+        TokioMutexWrapped::create(t, None)
+    }
+
+    /// See [`crate::debugging_locks::RwLockWrapped::new_named`].
+    #[track_caller]
+    pub fn new_named(name: &'static str, t: T) -> TokioMutexWrapped<T> {
+        TokioMutexWrapped::create(t, Some(name))
+    }
+
+    #[track_caller]
+    fn create(t: T, name: Option<&'static str>) -> TokioMutexWrapped<T> {
+        info!(
+            "NEW WRAPPED TOKIO MUTEX {}(v{})",
+            name.map(|name| format!("{} ", name)).unwrap_or_default(),
+            LIB_VERSION
+        );
+        TokioMutexWrapped {
+            inner: Mutex::new(t),
+            state: LockState::new(stack_created_if_enabled(), name, Location::caller()),
+        }
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        self.state.name
+    }
+
+    /// See [`crate::debugging_locks::RwLockWrapped::with_event_handler`].
+    pub fn with_event_handler(self, handler: Arc<dyn LockEventHandler>) -> TokioMutexWrapped<T> {
+        self.state.set_event_handler(handler);
+        self
+    }
+
+    pub fn to_mutex(&self) -> &Mutex<T> {
+        &self.inner
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> impl Future<Output = TokioMutexGuardWrapped<'_, T>> {
+        let location = Location::caller();
+        async move {
+            let guard = match self.inner.try_lock() {
+                Ok(guard) => guard,
+                Err(_) if !diagnostics_enabled() => self.inner.lock().await,
+                Err(_) => {
+                    acquire_reporting(&self.state, LockAccess::Lock, location, self.inner.lock())
+                        .await
+                }
+            };
+            TokioMutexGuardWrapped {
+                holder: register_holder(&self.state, LockAccess::Lock, location),
+                inner: ManuallyDrop::new(guard),
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: Default> Default for TokioMutexWrapped<T> {
+    /// Creates a new `Mutex<T>`, with the `Default` value for T.
+    #[track_caller]
+    fn default() -> TokioMutexWrapped<T> {
+        TokioMutexWrapped::new(Default::default())
+    }
+}
+
+/// RAII guard of [`TokioRwLockWrapped::read`]; the holder is tracked until the guard is dropped.
+pub struct TokioRwLockReadGuardWrapped<'a, T: ?Sized> {
+    holder: Option<HolderToken<'a>>,
+    // dropped explicitly - see Drop
+    inner: ManuallyDrop<RwLockReadGuard<'a, T>>,
+}
+
+/// RAII guard of [`TokioRwLockWrapped::write`]; the holder is tracked until the guard is dropped.
+pub struct TokioRwLockWriteGuardWrapped<'a, T: ?Sized> {
+    holder: Option<HolderToken<'a>>,
+    // dropped explicitly - see Drop
+    inner: ManuallyDrop<RwLockWriteGuard<'a, T>>,
+}
+
+/// RAII guard of [`TokioMutexWrapped::lock`]; the holder is tracked until the guard is dropped.
+pub struct TokioMutexGuardWrapped<'a, T: ?Sized> {
+    holder: Option<HolderToken<'a>>,
+    // dropped explicitly - see Drop
+    inner: ManuallyDrop<MutexGuard<'a, T>>,
+}
+
+impl<T: ?Sized> Deref for TokioRwLockReadGuardWrapped<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner.deref()
+    }
+}
+
+impl<T: ?Sized> Deref for TokioRwLockWriteGuardWrapped<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner.deref()
+    }
+}
+
+impl<T: ?Sized> DerefMut for TokioRwLockWriteGuardWrapped<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner.deref_mut()
+    }
+}
+
+impl<T: ?Sized> Deref for TokioMutexGuardWrapped<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner.deref()
+    }
+}
+
+impl<T: ?Sized> DerefMut for TokioMutexGuardWrapped<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner.deref_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for TokioRwLockReadGuardWrapped<'_, T> {
+    fn drop(&mut self) {
+        let inner = &mut self.inner;
+        // SAFETY: inner is not accessed after this point
+        match &mut self.holder {
+            Some(holder) => holder.release(|| unsafe { ManuallyDrop::drop(inner) }),
+            None => unsafe { ManuallyDrop::drop(inner) },
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TokioRwLockReadGuardWrapped<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Drop for TokioRwLockWriteGuardWrapped<'_, T> {
+    fn drop(&mut self) {
+        let inner = &mut self.inner;
+        // SAFETY: inner is not accessed after this point
+        match &mut self.holder {
+            Some(holder) => holder.release(|| unsafe { ManuallyDrop::drop(inner) }),
+            None => unsafe { ManuallyDrop::drop(inner) },
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TokioRwLockWriteGuardWrapped<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Drop for TokioMutexGuardWrapped<'_, T> {
+    fn drop(&mut self) {
+        let inner = &mut self.inner;
+        // SAFETY: inner is not accessed after this point
+        match &mut self.holder {
+            Some(holder) => holder.release(|| unsafe { ManuallyDrop::drop(inner) }),
+            None => unsafe { ManuallyDrop::drop(inner) },
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TokioMutexGuardWrapped<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+// None while diagnostics are disabled: no stack capture, the guard just unlocks
+fn register_holder<'a>(
+    state: &'a LockState,
+    access: LockAccess,
+    location: &'static Location<'static>,
+) -> Option<HolderToken<'a>> {
+    if !diagnostics_enabled() {
+        return None;
+    }
+    Some(state.register_task_holder(access, location, capture_caller_stack()))
+}
+
+// registers the task as waiter until `acquire` completes: the watchdog thread reports it
+// (stack symbolization and event handlers never run on the runtime worker thread)
+async fn acquire_reporting<G>(
+    state: &LockState,
+    access: LockAccess,
+    location: &'static Location<'static>,
+    acquire: impl Future<Output = G>,
+) -> G {
+    // captured up front: the wait time is recorded by call site, short waits included
+    let _waiter: WaiterToken = state.register_task_waiter(access, location, capture_caller_stack());
+    watchdog::ensure_started();
+    acquire.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock_events::BlockedEvent;
+    use crate::lock_stats::call_site_stats;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::Mutex as StdMutex;
+    use std::time::{Duration, Instant};
+
+    // access and number of holders of all blocked events
+    #[derive(Default)]
+    struct BlockedRecorder(StdMutex<Vec<(LockAccess, usize)>>);
+
+    impl LockEventHandler for BlockedRecorder {
+        fn handle_blocked(&self, event: &BlockedEvent) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((event.access, event.holders.len()));
+        }
+    }
+
+    // read from a task while the write guard is held for `hold`
+    async fn read_blocked(lock: &Arc<TokioRwLockWrapped<i32>>, hold: Duration) -> i32 {
+        let write = lock.write().await;
+        let reader = {
+            let lock = lock.clone();
+            tokio::spawn(async move { *lock.read().await })
+        };
+        tokio::time::sleep(hold).await;
+        drop(write);
+        reader.await.unwrap()
+    }
+
+    #[tokio::test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    async fn report_blocked_read_while_write_is_held() {
+        let recorder = Arc::new(BlockedRecorder::default());
+        let lock = Arc::new(TokioRwLockWrapped::new(42).with_event_handler(recorder.clone()));
+
+        // shorter than report_after
+        assert_eq!(42, read_blocked(&lock, Duration::ZERO).await);
+        assert!(recorder.0.lock().unwrap().is_empty());
+        assert_eq!(42, read_blocked(&lock, Duration::from_millis(50)).await);
+
+        let blocked = recorder.0.lock().unwrap().clone();
+        assert!(!blocked.is_empty());
+        assert!(blocked.iter().all(|event| *event == (LockAccess::Read, 1)));
+        // short and long waits are keyed like the holds of the same caller
+        let call_sites = call_site_stats(lock.state.locktag());
+        let reads = call_sites
+            .iter()
+            .find(|call_site| call_site.wait_histogram.count() > 0)
+            .unwrap();
+        assert_eq!(2, reads.wait_histogram.count());
+        assert_eq!(2, reads.hold_histogram.count());
+    }
+
+    // the first blocked event waits for a signal of the test task and replies whether it got it
+    struct SignalledHandler {
+        entered: AtomicBool,
+        channels: StdMutex<Option<(Receiver<()>, Sender<bool>)>>,
+    }
+
+    impl LockEventHandler for SignalledHandler {
+        fn handle_blocked(&self, _event: &BlockedEvent) {
+            let Some((signal, reply)) = self.channels.lock().unwrap().take() else {
+                return;
+            };
+            self.entered.store(true, Ordering::SeqCst);
+            let signalled = signal.recv_timeout(Duration::from_secs(5)).is_ok();
+            reply.send(signalled).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    async fn report_blocked_without_blocking_the_worker_thread() {
+        let (signal, signal_receiver) = mpsc::channel();
+        let (reply_sender, reply) = mpsc::channel();
+        let handler = Arc::new(SignalledHandler {
+            entered: AtomicBool::new(false),
+            channels: StdMutex::new(Some((signal_receiver, reply_sender))),
+        });
+        let lock = Arc::new(TokioRwLockWrapped::new(42).with_event_handler(handler.clone()));
+
+        let write = lock.write().await;
+        let reader = {
+            let lock = lock.clone();
+            tokio::spawn(async move { *lock.read().await })
+        };
+        // the single worker thread keeps running this task while the handler is busy
+        let since = Instant::now();
+        while !handler.entered.load(Ordering::SeqCst) && since.elapsed() < Duration::from_secs(5) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        signal.send(()).unwrap();
+        drop(write);
+
+        assert_eq!(42, reader.await.unwrap());
+        assert!(reply.recv_timeout(Duration::from_secs(10)).unwrap());
+    }
+}
@@ -0,0 +1,347 @@
+use crate::lock_events::{
+    dispatch_blocked_event, dispatch_held_too_long_event, BlockedEvent, HeldTooLongEvent,
+};
+use crate::lock_registry::{all_locks, has_waiters, LockHolder, LockState, LockWaiter};
+use crate::stacktrace_util::BacktrackError;
+use crate::thresholds_config::{
+    held_too_long_for, min_held_too_long, thresholds_config, thresholds_config_for,
+    MIN_WATCHDOG_INTERVAL,
+};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// waiters block on the real lock; the watchdog thread reports:
+// - waiters which are blocked longer than ThresholdsConfig::report_after (see check_waiters)
+// - locks which are held longer than ThresholdsConfig::held_too_long:
+//   - on release (guard dropped) - see on_released
+//   - while still held - see check_held_locks
+// without waiters the watchdog parks until the next check of the held locks (if any) or until
+// a waiter gets registered - see park and unpark
+
+static WATCHDOG_STARTED: AtomicBool = AtomicBool::new(false);
+// holders reported by the watchdog while still held
+static REPORTED_HOLDERS: Mutex<Option<HashSet<u64>>> = Mutex::new(None);
+static PARKED: AtomicBool = AtomicBool::new(false);
+static PARK_LOCK: Mutex<()> = Mutex::new(());
+static UNPARK: Condvar = Condvar::new();
+
+/// Start a background thread which checks all waiters every `interval` while there are any.
+///
+/// Blocked waiters get reported according to the thresholds; each holder exceeding the
+/// held-too-long threshold gets reported once while still held (checked every 10% of the threshold).
+/// Without waiters and held-too-long thresholds the thread sleeps until the next waiter arrives.
+/// The watchdog gets started implicitly (with [`crate::thresholds_config::ThresholdsConfig::watchdog_interval`])
+/// on first contention. Calling this more than once has no effect; `interval` is at least 1ms.
+pub fn start_watchdog(interval: Duration) {
+    if WATCHDOG_STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let interval = interval.max(MIN_WATCHDOG_INTERVAL);
+    thread::Builder::new()
+        .name("lock-watchdog".to_string())
+        .spawn(move || {
+            let mut last_held_check = Instant::now();
+            loop {
+                if has_waiters() {
+                    thread::sleep(interval);
+                    check_waiters();
+                } else {
+                    let threshold = min_held_too_long();
+                    let next_held_check = threshold.map(|threshold| {
+                        (threshold / 10)
+                            .max(interval)
+                            .saturating_sub(last_held_check.elapsed())
+                    });
+                    park(next_held_check, threshold);
+                }
+                // resolution of 10% of the (smallest) threshold is good enough
+                if let Some(threshold) = min_held_too_long() {
+                    if last_held_check.elapsed() >= threshold / 10 {
+                        last_held_check = Instant::now();
+                        check_held_locks();
+                    }
+                }
+            }
+        })
+        .expect("failed to spawn lock watchdog thread");
+}
+
+// blocks until a waiter gets registered, the held-too-long threshold changes or `timeout` elapsed
+fn park(timeout: Option<Duration>, threshold: Option<Duration>) {
+    let guard = PARK_LOCK.lock().unwrap();
+    // note: SeqCst pairs with unpark - either the waiter is seen here or PARKED there
+    PARKED.store(true, Ordering::SeqCst);
+    let idle = |_: &mut ()| !has_waiters() && min_held_too_long() == threshold;
+    let _guard = match timeout {
+        None => UNPARK.wait_while(guard, idle).unwrap(),
+        Some(timeout) => UNPARK.wait_timeout_while(guard, timeout, idle).unwrap().0,
+    };
+    PARKED.store(false, Ordering::SeqCst);
+}
+
+// called after a waiter got registered or the held-too-long thresholds changed
+pub(crate) fn unpark() {
+    if PARKED.load(Ordering::SeqCst) {
+        let _guard = PARK_LOCK.lock().unwrap();
+        UNPARK.notify_one();
+    }
+}
+
+pub(crate) fn ensure_started() {
+    if !WATCHDOG_STARTED.load(Ordering::Relaxed) {
+        start_watchdog(thresholds_config().watchdog_interval);
+    }
+}
+
+fn check_waiters() {
+    for lock in all_locks() {
+        let config = thresholds_config_for(lock.name);
+        for (waiter, elapsed) in lock.due_waiters(&config) {
+            report_blocked(&lock, &waiter, elapsed);
+        }
+    }
+}
+
+// note: dispatched from the watchdog thread - a slow handler delays all reports
+fn report_blocked(state: &LockState, waiter: &LockWaiter, elapsed: Duration) {
+    let holders = state.holders();
+    // symbolize lazy - only if reported
+    let stack_caller = match (&waiter.stack, waiter.stack_error) {
+        (Some(stacktrace), _) => stacktrace.resolve(),
+        (None, error) => Err(error.unwrap_or(BacktrackError::NoStartFrame)),
+    };
+    let event = BlockedEvent {
+        locktag: state.locktag(),
+        name: state.name,
+        access: waiter.access,
+        location: waiter.location,
+        created_at: state.created_at,
+        thread: &waiter.thread,
+        since: waiter.since,
+        elapsed,
+        attempt: waiter.reports,
+        stack_created: state.stack_created.as_deref(),
+        holders: &holders,
+        stack_caller: stack_caller.as_ref().ok().copied(),
+        stack_caller_error: stack_caller.err(),
+    };
+    dispatch_blocked_event(state.event_handler().as_ref(), &event);
+}
+
+fn check_held_locks() {
+    let mut still_held: HashSet<u64> = HashSet::new();
+    for lock in all_locks() {
+        let threshold = held_too_long_for(lock.name);
+        for holder in lock.holders() {
+            still_held.insert(holder.holder_id);
+            let held_for = holder.since.elapsed();
+            if threshold.is_none_or(|threshold| held_for < threshold) {
+                continue;
+            }
+            let first_report = REPORTED_HOLDERS
+                .lock()
+                .unwrap()
+                .get_or_insert_with(HashSet::new)
+                .insert(holder.holder_id);
+            if first_report {
+                report_held_too_long(&lock, &holder, held_for, false);
+            }
+        }
+    }
+
+    if let Some(reported) = REPORTED_HOLDERS.lock().unwrap().as_mut() {
+        reported.retain(|holder_id| still_held.contains(holder_id));
+    }
+}
+
+// called on the releasing thread after the lock was released
+pub(crate) fn on_released(state: &LockState, holder: &LockHolder) {
+    let threshold = match held_too_long_for(state.name) {
+        None => return,
+        Some(threshold) => threshold,
+    };
+    let held_for = holder.since.elapsed();
+    if held_for < threshold {
+        return;
+    }
+    if let Some(reported) = REPORTED_HOLDERS.lock().unwrap().as_mut() {
+        reported.remove(&holder.holder_id);
+    }
+    report_held_too_long(state, holder, held_for, true);
+}
+
+fn report_held_too_long(
+    state: &LockState,
+    holder: &LockHolder,
+    held_for: Duration,
+    released: bool,
+) {
+    let event = HeldTooLongEvent {
+        locktag: state.locktag(),
+        name: state.name,
+        holder,
+        held_for,
+        released,
+        created_at: state.created_at,
+        stack_created: state.stack_created.as_deref(),
+    };
+    dispatch_held_too_long_event(state.event_handler().as_ref(), &event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugging_locks::{MutexWrapped, RwLockWrapped};
+    use crate::lock_events::{
+        reset_global_event_handler, set_global_event_handler, LockAccess, LockEventHandler,
+        GLOBAL_HANDLER_TESTS,
+    };
+    use crate::thresholds_config::{set_named_thresholds_config, ThresholdsConfig};
+    use std::panic::Location;
+    use std::sync::{Arc, PoisonError};
+    use std::thread::ThreadId;
+
+    // `released` of all held too long events
+    #[derive(Default)]
+    struct HeldTooLongRecorder(Mutex<Vec<bool>>);
+
+    impl LockEventHandler for HeldTooLongRecorder {
+        fn handle_blocked(&self, _event: &BlockedEvent) {}
+
+        fn handle_held_too_long(&self, event: &HeldTooLongEvent) {
+            self.0.lock().unwrap().push(event.released);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn held_too_long_config_reports_lock_still_held() {
+        let config = ThresholdsConfig::default().with_held_too_long(Some(Duration::from_millis(5)));
+        set_named_thresholds_config("still_held_test", config);
+        let recorder = Arc::new(HeldTooLongRecorder::default());
+        let lock =
+            MutexWrapped::new_named("still_held_test", 0).with_event_handler(recorder.clone());
+
+        // uncontended - only the config starts the watchdog
+        let guard = lock.lock().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let still_held = recorder.0.lock().unwrap().clone();
+        drop(guard);
+
+        assert_eq!(vec![false], still_held);
+        assert_eq!(vec![false, true], *recorder.0.lock().unwrap());
+    }
+
+    // summary of a blocked event: waiter and holders
+    #[derive(Clone)]
+    struct Blocked {
+        name: Option<String>,
+        access: LockAccess,
+        thread_id: ThreadId,
+        holders: Vec<(LockAccess, &'static Location<'static>)>,
+    }
+
+    #[derive(Default)]
+    struct BlockedRecorder(Mutex<Vec<Blocked>>);
+
+    impl LockEventHandler for BlockedRecorder {
+        fn handle_blocked(&self, event: &BlockedEvent) {
+            self.0.lock().unwrap().push(Blocked {
+                name: event.name.map(str::to_string),
+                access: event.access,
+                thread_id: event.thread.thread_id,
+                holders: event
+                    .holders
+                    .iter()
+                    .map(|holder| (holder.access, holder.location))
+                    .collect(),
+            });
+        }
+    }
+
+    // read on another thread while the write guard is held until `release`; returns the id of the
+    // reader thread and the line of the write
+    fn read_blocked(lock: &Arc<RwLockWrapped<i32>>, release: impl Fn() -> bool) -> (ThreadId, u32) {
+        let (write, line) = (lock.write().unwrap(), line!());
+        let reader = {
+            let lock = lock.clone();
+            thread::spawn(move || *lock.read().unwrap())
+        };
+        let since = Instant::now();
+        while !release() && since.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(write);
+        let reader_id = reader.thread().id();
+        assert_eq!(42, reader.join().unwrap());
+        (reader_id, line)
+    }
+
+    // number of events of the lock `name` (other tests may report meanwhile)
+    fn count(recorder: &BlockedRecorder, name: &str) -> usize {
+        let events = recorder.0.lock().unwrap();
+        events
+            .iter()
+            .filter(|event| event.name.as_deref() == Some(name))
+            .count()
+    }
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn report_blocked_reader_to_lock_handler() {
+        let recorder = Arc::new(BlockedRecorder::default());
+        let lock = Arc::new(RwLockWrapped::new(42).with_event_handler(recorder.clone()));
+
+        let (reader_id, line) = read_blocked(&lock, || !recorder.0.lock().unwrap().is_empty());
+
+        let blocked = recorder.0.lock().unwrap()[0].clone();
+        assert_eq!(LockAccess::Read, blocked.access);
+        assert_eq!(reader_id, blocked.thread_id);
+        assert_eq!(1, blocked.holders.len());
+        let (holder_access, holder_location) = blocked.holders[0];
+        assert_eq!(LockAccess::Write, holder_access);
+        assert_eq!(file!(), holder_location.file());
+        assert_eq!(line, holder_location.line());
+    }
+
+    #[test]
+    #[cfg_attr(feature = "passthrough", ignore)]
+    fn dispatch_to_lock_handler_then_global_handler() {
+        let _global = GLOBAL_HANDLER_TESTS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let lock_handler = Arc::new(BlockedRecorder::default());
+        let global_handler = Arc::new(BlockedRecorder::default());
+        let with_handler = Arc::new(
+            RwLockWrapped::new_named("with_handler", 42).with_event_handler(lock_handler.clone()),
+        );
+        let without_handler = Arc::new(RwLockWrapped::new_named("without_handler", 42));
+
+        set_global_event_handler(global_handler.clone());
+        read_blocked(&with_handler, || count(&lock_handler, "with_handler") > 0);
+        read_blocked(&without_handler, || {
+            count(&global_handler, "without_handler") > 0
+        });
+        reset_global_event_handler();
+        // a report may still be in flight
+        thread::sleep(Duration::from_millis(20));
+        let reported_to_global = count(&global_handler, "without_handler");
+
+        // blocked again: reported by the LogEventHandler
+        let since = Instant::now();
+        read_blocked(&without_handler, || {
+            since.elapsed() > Duration::from_millis(50)
+        });
+
+        assert!(count(&lock_handler, "with_handler") > 0);
+        assert_eq!(0, count(&global_handler, "with_handler"));
+        assert!(reported_to_global > 0);
+        assert_eq!(
+            reported_to_global,
+            count(&global_handler, "without_handler")
+        );
+    }
+}
@@ -0,0 +1,94 @@
+// own test binary: the global switch would race with the unit tests; the tests of this file
+// toggle one after the other (see TOGGLE)
+
+use rust_debugging_locks::debug_symbols::{self, DebugSymbolsPolicy};
+use rust_debugging_locks::debugging_locks::{set_diagnostics_enabled, MutexWrapped};
+use rust_debugging_locks::lock_events::{
+    set_global_event_handler, BlockedEvent, HeldTooLongEvent, LockEventHandler,
+};
+use rust_debugging_locks::lock_order::{
+    disable_lock_order_validation, enable_lock_order_validation, LockOrderViolation,
+};
+use rust_debugging_locks::lock_stats::lock_stats_by_name;
+use rust_debugging_locks::thresholds_config::{
+    set_named_thresholds_config, set_thresholds_config, ThresholdsConfig,
+};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+static TOGGLE: Mutex<()> = Mutex::new(());
+
+#[test]
+#[cfg_attr(feature = "passthrough", ignore)]
+fn enabling_diagnostics_applies_the_debug_symbols_policy() {
+    let _toggle = TOGGLE.lock().unwrap();
+    set_thresholds_config(
+        ThresholdsConfig::default().with_debug_symbols_policy(DebugSymbolsPolicy::Panic),
+    );
+
+    // no self check yet: created while disabled
+    set_diagnostics_enabled(false);
+    let lock = MutexWrapped::new(0);
+    set_diagnostics_enabled(true);
+
+    // the self check ran on enabling; acquisition only reads its result
+    *lock.lock().unwrap() += 1;
+    assert_eq!(1, *lock.lock().unwrap());
+    assert!(debug_symbols::self_check().is_ok());
+}
+
+// all events except blocked ones
+#[derive(Default)]
+struct EventRecorder(Mutex<Vec<String>>);
+
+impl LockEventHandler for EventRecorder {
+    fn handle_blocked(&self, _event: &BlockedEvent) {}
+
+    fn handle_held_too_long(&self, event: &HeldTooLongEvent) {
+        let label = format!("held too long: {:?}", event.name);
+        self.0.lock().unwrap().push(label);
+    }
+
+    fn handle_lock_order_violation(&self, event: &LockOrderViolation) {
+        self.0.lock().unwrap().push(event.to_string());
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "passthrough", ignore)]
+fn guard_acquired_while_disabled_leaves_no_residue() {
+    let _toggle = TOGGLE.lock().unwrap();
+    let recorder = Arc::new(EventRecorder::default());
+    set_global_event_handler(recorder.clone());
+    let held_too_long =
+        ThresholdsConfig::default().with_held_too_long(Some(Duration::from_millis(10)));
+    set_named_thresholds_config("toggle_a", held_too_long);
+    let a = MutexWrapped::new_named("toggle_a", 0).with_event_handler(recorder.clone());
+    let b = MutexWrapped::new_named("toggle_b", 0);
+    enable_lock_order_validation();
+
+    set_diagnostics_enabled(false);
+    let guard_a = a.lock().unwrap();
+    set_diagnostics_enabled(true);
+    // a is not tracked: neither learned as a -> b nor reported as held too long
+    drop(b.lock().unwrap());
+    thread::sleep(Duration::from_millis(50));
+    drop(guard_a);
+
+    // b -> a is no inversion
+    let guard_b = b.lock().unwrap();
+    drop(a.lock().unwrap());
+    drop(guard_b);
+    disable_lock_order_validation();
+    thread::sleep(Duration::from_millis(20));
+
+    assert!(recorder.0.lock().unwrap().is_empty());
+    let stats_a = lock_stats_by_name("toggle_a").unwrap();
+    assert_eq!(1, stats_a.acquisitions());
+    assert_eq!(1, stats_a.hold_histogram.count());
+    assert!(stats_a.hold_max < Duration::from_millis(50));
+    let stats_b = lock_stats_by_name("toggle_b").unwrap();
+    assert_eq!(2, stats_b.acquisitions());
+    assert_eq!(2, stats_b.hold_histogram.count());
+}